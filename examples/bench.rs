@@ -0,0 +1,113 @@
+// Keep-alive load generator for comparing server backends
+// Usage: cargo run --release --example bench -- <addr> <path> [connections] [seconds]
+
+use std::{env, io::{self, Read, Write}, net::TcpStream, os::unix::io::AsRawFd, process, time::{Duration, Instant}};
+
+
+// A connection & how far it's read into the current response
+struct Client {
+    stream: TcpStream,
+    head: Vec<u8>,
+    body_left: Option<usize> // None while reading the head
+}
+
+impl Client {
+    fn connect(addr: &str, request: &[u8]) -> io::Result<Self> {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        stream.write_all(request)?;
+
+        Ok(Client { stream, head: vec![], body_left: None })
+    }
+
+    // Consume received bytes, returning how many responses & body bytes they finished
+    fn receive(&mut self, mut data: &[u8], request: &[u8]) -> io::Result<(u64, u64)> {
+        let (mut done, mut bytes) = (0, 0);
+
+        while !data.is_empty() {
+            match self.body_left {
+                None => {
+                    self.head.push(data[0]);
+                    data = &data[1..];
+
+                    if self.head.ends_with(b"\r\n\r\n") {
+                        self.body_left = Some(content_length(&self.head));
+                        self.head.clear();
+                    }
+                },
+                Some(left) => {
+                    let take = left.min(data.len());
+                    data = &data[take..];
+                    bytes += take as u64;
+                    self.body_left = Some(left - take);
+                }
+            }
+
+            if self.body_left == Some(0) {
+                self.body_left = None;
+                self.stream.write_all(request)?;
+                done += 1;
+            }
+        }
+
+        Ok((done, bytes))
+    }
+}
+
+fn content_length(head: &[u8]) -> usize {
+    String::from_utf8_lossy(head).lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+
+fn main() -> io::Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    if args.len() < 2 {
+        eprintln!("Usage: bench <addr> <path> [connections] [seconds]");
+        process::exit(1);
+    }
+
+    let (addr, path) = (&args[0], &args[1]);
+    let conns: usize = args.get(2).and_then(|n| n.parse().ok()).unwrap_or(16);
+    let secs: f64 = args.get(3).and_then(|n| n.parse().ok()).unwrap_or(5.0);
+    let request = format!("GET {path} HTTP/1.1\r\nHost: bench\r\n\r\n").into_bytes();
+
+    let mut clients = (0..conns).map(|_| Client::connect(addr, &request)).collect::<io::Result<Vec<_>>>()?;
+    let mut buf = vec![0; 1 << 16];
+    let (mut done, mut bytes) = (0u64, 0u64);
+    let start = Instant::now();
+
+    while start.elapsed() < Duration::from_secs_f64(secs) {
+        let mut fds: Vec<_> = clients.iter()
+            .map(|c| libc::pollfd { fd: c.stream.as_raw_fd(), events: libc::POLLIN, revents: 0 })
+            .collect();
+
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, 1000) } <= 0 {
+            continue;
+        }
+
+        for (client, fd) in clients.iter_mut().zip(&fds) {
+            if fd.revents & (libc::POLLIN | libc::POLLHUP) == 0 {
+                continue;
+            }
+
+            // Reconnect when the server closes a connection
+            match client.stream.read(&mut buf)? {
+                0 => *client = Client::connect(addr, &request)?,
+                len => {
+                    let (d, b) = client.receive(&buf[..len], &request)?;
+                    done += d;
+                    bytes += b;
+                }
+            }
+        }
+    }
+
+    let elapsed = start.elapsed().as_secs_f64();
+    println!("{path} conns={conns}: {:.0} req/s, {:.1} MB/s", done as f64 / elapsed, bytes as f64 / elapsed / 1e6);
+    Ok(())
+}
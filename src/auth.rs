@@ -0,0 +1,387 @@
+// HTTP Basic & Digest authentication
+
+use crate::{log, path::Prefixes, http::{self, response::{Response, ResponseBuilder}, Status}};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use httparse::Request;
+use md5::Md5;
+use sha1::{Digest, Sha1};
+use std::{collections::HashMap, fs, io::{self, Read}, path::Path, str::FromStr, time::{SystemTime, UNIX_EPOCH}};
+
+
+// How long a Digest nonce stays valid
+const NONCE_LIFETIME: u64 = 300;
+
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn md5_hex(data: &str) -> String {
+    hex(&Md5::digest(data.as_bytes()))
+}
+
+// Compare without returning early, so timing doesn't reveal how much matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheme {
+    Basic,
+    Digest
+}
+
+impl FromStr for Scheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "basic" => Ok(Scheme::Basic),
+            "digest" => Ok(Scheme::Digest),
+            _ => Err(format!("Unknown auth scheme '{s}'"))
+        }
+    }
+}
+
+
+// Credentials for one protected path prefix
+#[derive(Debug)]
+pub struct Realm {
+    pub name: String,
+    pub scheme: Scheme,
+    users: HashMap<String, String> // User -> password hash, or HA1 for Digest
+}
+
+impl Realm {
+    pub fn new<S: Into<String>>(name: S, scheme: Scheme) -> Self {
+        Realm { name: name.into(), scheme, users: HashMap::new() }
+    }
+
+    // Load an htpasswd file for Basic, or an htdigest file for Digest
+    pub fn load_users<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let source = fs::read_to_string(path)?;
+
+        for line in source.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+            match self.scheme {
+                // user:hash
+                Scheme::Basic => if let Some((user, hash)) = line.split_once(':') {
+                    self.users.insert(user.into(), hash.into());
+                },
+                // user:realm:HA1, only keeping users in this realm
+                Scheme::Digest => if let [user, realm, ha1] = line.splitn(3, ':').collect::<Vec<_>>()[..] {
+                    if realm == self.name {
+                        self.users.insert(user.into(), ha1.to_ascii_lowercase());
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn verify_password(&self, user: &str, password: &str) -> bool {
+        let hash = match self.users.get(user) {
+            Some(h) => h,
+            None => return false
+        };
+
+        if hash.starts_with("$2") {
+            bcrypt::verify(password, hash).unwrap_or(false)
+        }
+        else if let Some(sha) = hash.strip_prefix("{SHA}") {
+            let digest = BASE64.encode(Sha1::digest(password.as_bytes()));
+            constant_time_eq(digest.as_bytes(), sha.as_bytes())
+        }
+        else {
+            log::warning!(Server, "Unsupported Password Hash"; "user" => user, "realm" => self.name);
+            false
+        }
+    }
+}
+
+
+// Parse 'key=value, key="quoted, value"' pairs from a Digest header
+fn parse_params(data: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    let mut rest = data.trim();
+
+    while let Some((key, after)) = rest.split_once('=') {
+        let key = key.trim().trim_start_matches(',').trim().to_ascii_lowercase();
+        let after = after.trim_start();
+
+        let (value, remaining) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"').unwrap_or(quoted.len());
+                (&quoted[..end], quoted.get(end + 1..).unwrap_or(""))
+            },
+            None => after.split_at(after.find(',').unwrap_or(after.len()))
+        };
+
+        params.insert(key, value.trim().to_string());
+        rest = remaining.trim_start().trim_start_matches(',');
+    }
+
+    params
+}
+
+
+enum Outcome {
+    Allowed(String), // Authenticated user
+    Denied { stale: bool }
+}
+
+
+#[derive(Debug)]
+pub struct Auth {
+    realms: Prefixes<Realm>,
+    secret: String // For signing Digest nonces
+}
+
+impl Auth {
+    pub fn new() -> Self {
+        let mut secret = [0u8; 16];
+
+        if fs::File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut secret)).is_err() {
+            secret = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_le_bytes();
+        }
+
+        Auth { realms: Prefixes::new(), secret: hex(&secret) }
+    }
+
+    pub fn add(&mut self, prefix: &str, realm: Realm) {
+        self.realms.insert(prefix, realm);
+    }
+
+    fn nonce(&self, time: u64) -> String {
+        format!("{time:x}-{}", md5_hex(&format!("{time}:{}", self.secret)))
+    }
+
+    // Check a nonce was issued by us, returning whether it has expired
+    fn check_nonce(&self, nonce: &str) -> Option<bool> {
+        let (time, _) = nonce.split_once('-')?;
+        let time = u64::from_str_radix(time, 16).ok()?;
+
+        match constant_time_eq(self.nonce(time).as_bytes(), nonce.as_bytes()) {
+            true => Some(unix_time().saturating_sub(time) > NONCE_LIFETIME),
+            false => None
+        }
+    }
+
+    fn check_basic(&self, realm: &Realm, credentials: &str) -> Outcome {
+        let decoded = BASE64.decode(credentials.trim()).ok().and_then(|d| String::from_utf8(d).ok());
+
+        match decoded.as_deref().and_then(|d| d.split_once(':')) {
+            Some((user, password)) if realm.verify_password(user, password) => Outcome::Allowed(user.into()),
+            _ => Outcome::Denied { stale: false }
+        }
+    }
+
+    fn check_digest(&self, realm: &Realm, method: &str, path: &str, params: &str) -> Outcome {
+        let params = parse_params(params);
+        let param = |key: &str| params.get(key).map(String::as_str).unwrap_or("");
+
+        let (user, nonce, uri) = (param("username"), param("nonce"), param("uri"));
+
+        let ha1 = match realm.users.get(user) {
+            Some(ha1) if param("realm") == realm.name && uri == path => ha1,
+            _ => return Outcome::Denied { stale: false }
+        };
+
+        let stale = match self.check_nonce(nonce) {
+            Some(stale) => stale,
+            None => return Outcome::Denied { stale: false }
+        };
+
+        let ha2 = md5_hex(&format!("{method}:{uri}"));
+
+        let expected = match param("qop") {
+            "auth" => md5_hex(&format!("{ha1}:{nonce}:{}:{}:auth:{ha2}", param("nc"), param("cnonce"))),
+            "" => md5_hex(&format!("{ha1}:{nonce}:{ha2}")),
+            _ => return Outcome::Denied { stale: false }
+        };
+
+        match constant_time_eq(expected.as_bytes(), param("response").to_ascii_lowercase().as_bytes()) {
+            true if stale => Outcome::Denied { stale: true },
+            true => Outcome::Allowed(user.into()),
+            false => Outcome::Denied { stale: false }
+        }
+    }
+
+    fn challenge(&self, realm: &Realm, stale: bool) -> Response {
+        let value = match realm.scheme {
+            Scheme::Basic => format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm.name),
+            Scheme::Digest => format!(
+                "Digest realm=\"{}\", qop=\"auth\", algorithm=MD5, nonce=\"{}\"{}",
+                realm.name,
+                self.nonce(unix_time()),
+                if stale { ", stale=true" } else { "" }
+            )
+        };
+
+        ResponseBuilder::new()
+            .status(Status::Unauthorized)
+            .header("WWW-Authenticate", value)
+            .error_page()
+    }
+
+    // Check a request's credentials, giving the authenticated user if any,
+    // or the response to send instead
+    pub fn check(&self, path: &str, req: &Request) -> Result<Option<String>, Response> {
+        let realm = match self.realms.get(path) {
+            Some(realm) => realm,
+            None => return Ok(None)
+        };
+
+        let header = http::header(req, "Authorization").unwrap_or("");
+        let (scheme, credentials) = header.trim().split_once(' ').unwrap_or((header, ""));
+
+        let outcome = match scheme.parse::<Scheme>() {
+            Ok(Scheme::Basic) if realm.scheme == Scheme::Basic => self.check_basic(realm, credentials),
+            Ok(Scheme::Digest) if realm.scheme == Scheme::Digest => {
+                self.check_digest(realm, req.method.unwrap_or(""), req.path.unwrap_or(""), credentials)
+            },
+            _ => Outcome::Denied { stale: false }
+        };
+
+        match outcome {
+            Outcome::Allowed(user) => Ok(Some(user)),
+            Outcome::Denied { stale } => Err(self.challenge(realm, stale))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{testing::{self, path_variants}, Url};
+
+    fn check(auth: &Auth, target: &str, authorization: Option<&str>) -> Result<Option<String>, String> {
+        let headers: Vec<_> = authorization.map(|a| ("Authorization", a)).into_iter().collect();
+        let req = testing::request("GET", target, &headers);
+        let path = Url::parse(target).unwrap().normalized_path().unwrap();
+
+        // Denials give their challenge
+        auth.check(&path, &req).map_err(|res| String::from_utf8_lossy(&res.into_bytes().unwrap()).into_owned())
+    }
+
+    fn basic(user: &str, password: &str) -> String {
+        format!("Basic {}", BASE64.encode(format!("{user}:{password}")))
+    }
+
+    fn realm(scheme: Scheme, users: &[(&str, String)]) -> Auth {
+        let mut realm = Realm::new("Secret", scheme);
+        realm.users = users.iter().map(|(user, hash)| (user.to_string(), hash.clone())).collect();
+
+        let mut auth = Auth::new();
+        auth.add("/secret", realm);
+        auth
+    }
+
+    #[test]
+    fn realm_bypasses() {
+        let auth = realm(Scheme::Basic, &[]);
+
+        for target in path_variants("/secret/data.txt") {
+            assert!(check(&auth, &target, None).is_err(), "{target} wasn't protected");
+        }
+    }
+
+    #[test]
+    fn realm_segments() {
+        let mut auth = Auth::new();
+        auth.add("/secret/", Realm::new("Secret", Scheme::Basic));
+
+        assert!(check(&auth, "/secret", None).is_err());
+        assert!(check(&auth, "/secret/a/b", None).is_err());
+        assert_eq!(check(&auth, "/secrets/data.txt", None), Ok(None));
+        assert_eq!(check(&auth, "/public/secret", None), Ok(None));
+    }
+
+    #[test]
+    fn basic_bcrypt() {
+        let auth = realm(Scheme::Basic, &[("ann", bcrypt::hash("hunter2", 4).unwrap())]);
+
+        assert_eq!(check(&auth, "/secret/x", Some(&basic("ann", "hunter2"))), Ok(Some("ann".into())));
+        assert!(check(&auth, "/secret/x", Some(&basic("ann", "hunter3"))).is_err());
+        assert!(check(&auth, "/secret/x", Some(&basic("bob", "hunter2"))).is_err());
+        assert!(check(&auth, "/secret/x", Some("Basic !!!")).is_err());
+
+        let challenge = check(&auth, "/secret/x", None).unwrap_err();
+        assert!(challenge.starts_with("HTTP/1.1 401"));
+        assert!(challenge.contains("WWW-Authenticate: Basic realm=\"Secret\", charset=\"UTF-8\""));
+    }
+
+    #[test]
+    fn basic_sha() {
+        let hash = format!("{{SHA}}{}", BASE64.encode(Sha1::digest(b"hunter2")));
+        let auth = realm(Scheme::Basic, &[("ann", hash), ("bob", "plain".into())]);
+
+        assert_eq!(check(&auth, "/secret/x", Some(&basic("ann", "hunter2"))), Ok(Some("ann".into())));
+        assert!(check(&auth, "/secret/x", Some(&basic("ann", "hunter3"))).is_err());
+
+        // Unsupported hashes never match, even the plain password
+        assert!(check(&auth, "/secret/x", Some(&basic("bob", "plain"))).is_err());
+    }
+
+    #[test]
+    fn digest() {
+        let ha1 = md5_hex("ann:Secret:hunter2");
+        let auth = realm(Scheme::Digest, &[("ann", ha1.clone())]);
+
+        let header = |nonce: &str, uri: &str, ha1: &str| {
+            let ha2 = md5_hex(&format!("GET:{uri}"));
+            let response = md5_hex(&format!("{ha1}:{nonce}:00000001:abc:auth:{ha2}"));
+
+            format!("Digest username=\"ann\", realm=\"Secret\", nonce=\"{nonce}\", uri=\"{uri}\", qop=auth, nc=00000001, cnonce=\"abc\", response=\"{response}\"")
+        };
+        let nonce = auth.nonce(unix_time());
+
+        assert_eq!(check(&auth, "/secret/x", Some(&header(&nonce, "/secret/x", &ha1))), Ok(Some("ann".into())));
+        assert!(check(&auth, "/secret/x", Some(&header(&nonce, "/secret/x", &md5_hex("ann:Secret:hunter3")))).is_err());
+
+        // The digest must be for this request's URI
+        assert!(check(&auth, "/secret/x", Some(&header(&nonce, "/secret/y", &ha1))).is_err());
+
+        // Forged nonces are rejected, expired ones ask the client to retry
+        let forged = check(&auth, "/secret/x", Some(&header("1-abc", "/secret/x", &ha1))).unwrap_err();
+        assert!(!forged.contains("stale=true"));
+
+        let old = auth.nonce(unix_time() - NONCE_LIFETIME - 10);
+        let stale = check(&auth, "/secret/x", Some(&header(&old, "/secret/x", &ha1))).unwrap_err();
+        assert!(stale.contains("stale=true"));
+
+        // Basic credentials don't work for a Digest realm
+        assert!(check(&auth, "/secret/x", Some(&basic("ann", "hunter2"))).is_err());
+    }
+
+    #[test]
+    fn user_files() {
+        let dir = std::env::temp_dir().join(format!("ws-auth-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let htpasswd = dir.join("htpasswd");
+        fs::write(&htpasswd, "# users\nann:{SHA}x\n\nbob:$2y$05$abc\n").unwrap();
+
+        let mut realm = Realm::new("Secret", Scheme::Basic);
+        realm.load_users(&htpasswd).unwrap();
+        assert_eq!(realm.users.len(), 2);
+        assert_eq!(realm.users["bob"], "$2y$05$abc");
+
+        // Only users of this realm are kept from an htdigest file
+        let htdigest = dir.join("htdigest");
+        fs::write(&htdigest, "ann:Secret:ABCDEF\nbob:Other:123456\n").unwrap();
+
+        let mut realm = Realm::new("Secret", Scheme::Digest);
+        realm.load_users(&htdigest).unwrap();
+        assert_eq!(realm.users.len(), 1);
+        assert_eq!(realm.users["ann"], "abcdef");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,451 @@
+// In-memory file cache
+
+use crate::log;
+use flate2::{write::GzEncoder, Compression};
+use std::{cell::{Cell, RefCell}, collections::{BTreeMap, HashMap}, ffi::CString, fs, io::{self, Write}, os::unix::{ffi::OsStrExt, io::RawFd}, path::{Path, PathBuf}, rc::Rc, str::FromStr, time::{SystemTime, UNIX_EPOCH}};
+
+
+// Smallest file worth compressing
+const MIN_COMPRESS_SIZE: usize = 256;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Invalidate {
+    Mtime, // Check each file's modification time when it's served
+    Inotify // Drop files when the kernel reports a change
+}
+
+impl FromStr for Invalidate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mtime" => Ok(Invalidate::Mtime),
+            "inotify" => Ok(Invalidate::Inotify),
+            _ => Err(format!("Unknown cache invalidation '{s}'"))
+        }
+    }
+}
+
+
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    pub max_size: u64, // Total bytes, including compressed variants
+    pub max_entries: usize,
+    pub max_file_size: u64, // Larger files are always read from disk
+    pub invalidate: Invalidate,
+    pub compress: bool,
+    pub warm: bool // Load the served directory at startup
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            max_size: 64 << 20,
+            max_entries: 4096,
+            max_file_size: 1 << 20,
+            invalidate: Invalidate::Mtime,
+            compress: true,
+            warm: false
+        }
+    }
+}
+
+
+// A file's contents & what's needed to serve them
+#[derive(Debug)]
+pub struct File {
+    pub data: Rc<[u8]>, // Shared with the responses sending it
+    pub etag: String,
+    pub gzip: Option<Rc<[u8]>>,
+    modified: Option<SystemTime>,
+    last_used: Cell<u64>
+}
+
+impl File {
+    pub fn load(path: &Path, compress: bool) -> io::Result<Self> {
+        // Checked first, so a change while reading makes the file look stale rather than fresh
+        let modified = fs::metadata(path)?.modified().ok();
+        let data = fs::read(path)?;
+
+        let gzip = match compress && data.len() >= MIN_COMPRESS_SIZE {
+            true => gzip(&data).filter(|gz| gz.len() < data.len() * 9 / 10),
+            false => None
+        };
+
+        Ok(File {
+            etag: etag(data.len() as u64, modified),
+            data: data.into(),
+            gzip: gzip.map(Rc::from),
+            modified,
+            last_used: Cell::new(0)
+        })
+    }
+
+    fn size(&self) -> u64 {
+        (self.data.len() + self.gzip.as_ref().map_or(0, |gz| gz.len())) as u64
+    }
+}
+
+
+// An entity tag from a file's size & modification time
+pub fn etag(len: u64, modified: Option<SystemTime>) -> String {
+    let time = modified.and_then(|m| m.duration_since(UNIX_EPOCH).ok()).unwrap_or_default();
+    format!("\"{len:x}-{:x}{:08x}\"", time.as_secs(), time.subsec_nanos())
+}
+
+fn gzip(data: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data).ok()?;
+    encoder.finish().ok()
+}
+
+
+// File change notifications from the kernel
+#[derive(Debug)]
+struct Inotify {
+    fd: RawFd,
+    watches: RefCell<HashMap<i32, PathBuf>>
+}
+
+impl Inotify {
+    fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+
+        match fd {
+            -1 => Err(io::Error::last_os_error()),
+            fd => Ok(Inotify { fd, watches: RefCell::new(HashMap::new()) })
+        }
+    }
+
+    fn watch(&self, path: &Path) {
+        let Ok(c_path) = CString::new(path.as_os_str().as_bytes()) else { return };
+        let mask = libc::IN_MODIFY | libc::IN_ATTRIB | libc::IN_CLOSE_WRITE | libc::IN_DELETE_SELF | libc::IN_MOVE_SELF;
+
+        match unsafe { libc::inotify_add_watch(self.fd, c_path.as_ptr(), mask) } {
+            -1 => log::warning!(Serve, "Can't Watch File: {}", io::Error::last_os_error(); "path" => path.display()),
+            wd => { self.watches.borrow_mut().insert(wd, path.to_owned()); }
+        }
+    }
+
+    fn unwatch(&self, path: &Path) {
+        let mut watches = self.watches.borrow_mut();
+
+        if let Some(wd) = watches.iter().find(|(_, p)| p.as_path() == path).map(|(wd, _)| *wd) {
+            unsafe { libc::inotify_rm_watch(self.fd, wd) };
+            watches.remove(&wd);
+        }
+    }
+
+    // Paths changed since the last call
+    fn changed(&self) -> Vec<PathBuf> {
+        let mut changed = vec![];
+        let mut buf = [0u8; 4096];
+        let header = std::mem::size_of::<libc::inotify_event>();
+
+        loop {
+            let len = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+
+            if len <= 0 {
+                break;
+            }
+
+            let mut offset = 0;
+
+            while offset + header <= len as usize {
+                let event = unsafe { std::ptr::read_unaligned(buf.as_ptr().add(offset) as *const libc::inotify_event) };
+
+                if let Some(path) = self.watches.borrow().get(&event.wd) {
+                    changed.push(path.clone());
+                }
+
+                offset += header + event.len as usize;
+            }
+        }
+
+        changed
+    }
+}
+
+impl Drop for Inotify {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+
+#[derive(Debug)]
+pub struct FileCache {
+    config: CacheConfig,
+    entries: RefCell<HashMap<PathBuf, Rc<File>>>,
+    size: Cell<u64>,
+    clock: Cell<u64>, // Incremented on every use, for LRU eviction
+    recency: RefCell<BTreeMap<u64, PathBuf>>, // Cached paths by when they were last used
+    inotify: Option<Inotify>
+}
+
+impl FileCache {
+    pub fn new(config: CacheConfig) -> Self {
+        let inotify = match config.invalidate {
+            Invalidate::Inotify => Inotify::new()
+                .inspect_err(|e| log::warning!(Serve, "Can't Use Inotify, Checking Modification Times: {e}"))
+                .ok(),
+            Invalidate::Mtime => None
+        };
+
+        FileCache {
+            config,
+            entries: RefCell::new(HashMap::new()),
+            size: Cell::new(0),
+            clock: Cell::new(0),
+            recency: RefCell::new(BTreeMap::new()),
+            inotify
+        }
+    }
+
+    fn remove(&self, path: &Path) {
+        if let Some(file) = self.entries.borrow_mut().remove(path) {
+            self.size.set(self.size.get() - file.size());
+            self.recency.borrow_mut().remove(&file.last_used.get());
+        }
+        if let Some(ref inotify) = self.inotify {
+            inotify.unwatch(path);
+        }
+    }
+
+    // Drop least recently used files until 'extra' more bytes & one more entry fit
+    fn make_room(&self, extra: u64) {
+        while self.size.get() + extra > self.config.max_size || self.entries.borrow().len() >= self.config.max_entries {
+            let oldest = self.recency.borrow().first_key_value().map(|(_, path)| path.clone());
+
+            match oldest {
+                Some(path) => self.remove(&path),
+                None => break
+            }
+        }
+    }
+
+    // Whether files of this size are kept in memory
+    pub fn holds(&self, len: u64) -> bool {
+        len <= self.config.max_file_size && len <= self.config.max_size
+    }
+
+    fn is_fresh(&self, path: &Path, file: &File) -> bool {
+        match self.inotify {
+            Some(_) => true, // Changed files were already dropped
+            None => fs::metadata(path).and_then(|m| m.modified()).ok() == file.modified && file.modified.is_some()
+        }
+    }
+
+    // Get a file from the cache, reading & caching it if it's missing or stale
+    pub fn get(&self, path: &Path) -> io::Result<Rc<File>> {
+        if let Some(ref inotify) = self.inotify {
+            for changed in inotify.changed() {
+                self.remove(&changed);
+            }
+        }
+
+        self.clock.set(self.clock.get() + 1);
+
+        let cached = self.entries.borrow().get(path).cloned();
+
+        if let Some(file) = cached {
+            if self.is_fresh(path, &file) {
+                let mut recency = self.recency.borrow_mut();
+                recency.remove(&file.last_used.replace(self.clock.get()));
+                recency.insert(self.clock.get(), path.to_owned());
+                return Ok(file);
+            }
+
+            self.remove(path);
+        }
+
+        let len = fs::metadata(path)?.len();
+
+        if len > self.config.max_file_size {
+            return File::load(path, false).map(Rc::new);
+        }
+
+        let file = File::load(path, self.config.compress)?;
+        file.last_used.set(self.clock.get());
+
+        if file.size() <= self.config.max_size {
+            self.make_room(file.size());
+            self.insert(path, file)
+        }
+        else {
+            Ok(Rc::new(file))
+        }
+    }
+
+    fn insert(&self, path: &Path, file: File) -> io::Result<Rc<File>> {
+        let file = Rc::new(file);
+
+        if let Some(ref inotify) = self.inotify {
+            inotify.watch(path);
+        }
+
+        self.size.set(self.size.get() + file.size());
+        self.recency.borrow_mut().insert(file.last_used.get(), path.to_owned());
+        self.entries.borrow_mut().insert(path.to_owned(), file.clone());
+
+        Ok(file)
+    }
+
+    // Load files under 'dir' until the cache is full, skipping hidden ones
+    pub fn warm(&self, root: &Path) {
+        let mut dirs = vec![root.to_owned()];
+        let mut count = 0;
+
+        while let Some(dir) = dirs.pop() {
+            let Ok(entries) = fs::read_dir(&dir) else { continue };
+
+            for entry in entries.flatten() {
+                let path = entry.path();
+                let hidden = entry.file_name().as_bytes().starts_with(b".");
+
+                match entry.file_type() {
+                    Ok(t) if t.is_dir() && !hidden => dirs.push(path),
+                    Ok(t) if t.is_file() && !hidden => {
+                        if self.entries.borrow().len() >= self.config.max_entries || self.size.get() >= self.config.max_size {
+                            log::info!(Serve, "Cache Warmed"; "dir" => root.display(), "files" => count);
+                            return;
+                        }
+                        if entry.metadata().is_ok_and(|m| m.len() <= self.config.max_file_size) && self.get(&path).is_ok() {
+                            count += 1;
+                        }
+                    },
+                    _ => {}
+                }
+            }
+        }
+
+        log::info!(Serve, "Cache Warmed"; "dir" => root.display(), "files" => count);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ws-cache-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn cache(max_size: u64, max_entries: usize) -> FileCache {
+        FileCache::new(CacheConfig { max_size, max_entries, compress: false, ..CacheConfig::default() })
+    }
+
+    fn cached(cache: &FileCache, dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = cache.entries.borrow().keys()
+            .map(|path| path.strip_prefix(dir).unwrap().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn lru_eviction() {
+        let dir = dir("lru");
+        for name in ["a", "b", "c", "d"] {
+            fs::write(dir.join(name), name.repeat(10)).unwrap();
+        }
+
+        let cache = cache(1 << 20, 2);
+        let a = cache.get(&dir.join("a")).unwrap();
+        cache.get(&dir.join("b")).unwrap();
+
+        // Hits share the cached body instead of copying it
+        assert!(Rc::ptr_eq(&cache.get(&dir.join("a")).unwrap().data, &a.data));
+
+        cache.get(&dir.join("c")).unwrap();
+        assert_eq!(cached(&cache, &dir), ["a", "c"]);
+
+        cache.get(&dir.join("d")).unwrap();
+        assert_eq!(cached(&cache, &dir), ["c", "d"]);
+        assert_eq!(cache.recency.borrow().len(), 2);
+        assert_eq!(cache.size.get(), 20);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn size_limits() {
+        let dir = dir("size");
+        fs::write(dir.join("small"), [b'x'; 40]).unwrap();
+        fs::write(dir.join("medium"), [b'x'; 60]).unwrap();
+        fs::write(dir.join("large"), [b'x'; 200]).unwrap();
+
+        let cache = cache(100, 100);
+        cache.get(&dir.join("small")).unwrap();
+        cache.get(&dir.join("medium")).unwrap();
+        assert_eq!(cache.size.get(), 100);
+
+        // Too big to ever cache, so nothing is evicted for it
+        assert_eq!(cache.get(&dir.join("large")).unwrap().data.len(), 200);
+        assert_eq!(cached(&cache, &dir), ["medium", "small"]);
+        assert!(!cache.holds(200));
+
+        // Growing past what's left evicts the other file
+        fs::write(dir.join("small"), [b'y'; 50]).unwrap();
+        fs::File::options().write(true).open(dir.join("small")).unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+        cache.get(&dir.join("small")).unwrap();
+        assert_eq!(cached(&cache, &dir), ["small"]);
+        assert_eq!(cache.size.get(), 50);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn mtime_invalidation() {
+        let dir = dir("mtime");
+        let path = dir.join("page.html");
+        fs::write(&path, "old").unwrap();
+
+        let cache = cache(1 << 20, 100);
+        let old = cache.get(&path).unwrap();
+        assert_eq!(&*old.data, b"old");
+        assert!(Rc::ptr_eq(&cache.get(&path).unwrap(), &old));
+
+        // Same length, so only the modification time tells the versions apart
+        fs::write(&path, "new").unwrap();
+        fs::File::options().write(true).open(&path).unwrap()
+            .set_modified(old.modified.unwrap() + Duration::from_secs(1)).unwrap();
+
+        let new = cache.get(&path).unwrap();
+        assert_eq!(&*new.data, b"new");
+        assert_ne!(new.etag, old.etag);
+        assert_eq!(cache.size.get(), 3);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn warming() {
+        let dir = dir("warm");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        fs::write(dir.join("index.html"), "index").unwrap();
+        fs::write(dir.join("sub/app.js"), "app").unwrap();
+        fs::write(dir.join(".env"), "secret").unwrap();
+        fs::write(dir.join(".git/config"), "config").unwrap();
+        fs::write(dir.join("big.bin"), [0; 64]).unwrap();
+
+        let cache = FileCache::new(CacheConfig { max_file_size: 32, ..CacheConfig::default() });
+        cache.warm(&dir);
+        assert_eq!(cached(&cache, &dir), ["index.html", "sub/app.js"]);
+
+        // Stops once the cache is full
+        let cache = self::cache(1 << 20, 1);
+        cache.warm(&dir);
+        assert_eq!(cache.entries.borrow().len(), 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
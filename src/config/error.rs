@@ -3,7 +3,8 @@ pub enum ErrorKind {
     IOError,
     AddrError,
     InvalidSection,
-    InvalidKey
+    InvalidKey,
+    InvalidValue
 }
 
 
@@ -1,41 +1,72 @@
 // Parse config files
 
 use super::error;
-use std::{collections::HashMap, fs, path::Path};
+use std::{fs, path::Path};
 
 
 // Remove one pair of quotes from a string, if present
-fn remove_quotes<'a>(data: &'a str) -> &'a str {
+fn remove_quotes(data: &str) -> &str {
     match data.chars().next() {
         Some(c) if "'\"".contains(c) => {
-            if data.ends_with(c) {
-                return &data[1..data.len() - 1];
+            if data.len() > 1 && data.ends_with(c) {
+                &data[1..data.len() - 1]
             }
             else {
-                return data;
+                data
             }
         },
         _ => data
     }
 }
 
+// Find the first of 'patterns' outside of quotes, giving its position & length
+fn find_unquoted(data: &str, patterns: &[&str]) -> Option<(usize, usize)> {
+    let mut quote = None;
+
+    for (i, c) in data.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {},
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None => if let Some(p) = patterns.iter().find(|p| data[i..].starts_with(**p)) {
+                return Some((i, p.len()));
+            }
+        }
+    }
+
+    None
+}
+
 
 #[derive(Debug)]
 pub struct ConfigSection {
     pub name: String,
-    pub keys: HashMap<String, String>
+    pub arg: Option<String>, // e.g. "docs.local" in [host "docs.local"]
+    pub keys: Vec<(String, String)> // In file order, may contain duplicates
+}
+
+impl ConfigSection {
+    // Get the last value set for a key
+    pub fn get(&self, key: &str) -> Option<&String> {
+        self.keys.iter().rev().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
 }
 
 
 struct SectionBuilder(ConfigSection);
 
 impl SectionBuilder {
-    pub fn new<S: Into<String>>(name: S) -> Self {
-        SectionBuilder(ConfigSection { name: name.into(), keys: HashMap::new() })
+    pub fn new(header: &str) -> Self {
+        let (name, arg) = match header.trim().split_once(char::is_whitespace) {
+            Some((name, arg)) => (name, Some(remove_quotes(arg.trim()).to_string())),
+            None => (header.trim(), None)
+        };
+
+        SectionBuilder(ConfigSection { name: name.into(), arg, keys: vec![] })
     }
 
     pub fn add_keypair<S: Into<String>>(&mut self, key: S, value: S) {
-        self.0.keys.insert(key.into(), value.into());
+        self.0.keys.push((key.into(), value.into()));
     }
 
     pub fn build(self) -> ConfigSection {
@@ -62,7 +93,11 @@ impl ConfigFile {
         let mut section: Option<SectionBuilder> = None;
 
         for line in source.lines() {
-            let line = line.split_once('#').unwrap_or((line, "")).0; // Strip comments
+            // Strip comments, leaving any '#' in quoted strings
+            let line = match find_unquoted(line, &["#"]) {
+                Some((i, _)) => line[..i].trim(),
+                None => line.trim()
+            };
 
             if line.starts_with('[') && line.ends_with(']') {
                 if let Some(s) = section.take() {
@@ -74,9 +109,11 @@ impl ConfigFile {
             else if !line.is_empty() {
                 match section {
                     Some(ref mut s) => {
-                        let (key, val) = line
-                            .split_once(':')
-                            .unwrap_or(line.split_once("->").unwrap_or((line, "")));
+                        // Split at the first ':' or '->' outside of quotes, e.g. "/" -> "https://..."
+                        let (key, val) = match find_unquoted(line, &[":", "->"]) {
+                            Some((i, len)) => (&line[..i], &line[i + len..]),
+                            None => (line, "")
+                        };
 
                         let key = remove_quotes(key.trim());
                         let val = remove_quotes(val.trim());
@@ -88,6 +125,10 @@ impl ConfigFile {
             }
         }
 
+        if let Some(s) = section.take() {
+            cfg.0.push(s.build());
+        }
+
         Ok(cfg)
     }
 }
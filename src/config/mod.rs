@@ -3,9 +3,9 @@
 mod error;
 mod file;
 
-use crate::path::PathMatch;
-use clap::{arg, Arg, crate_authors, crate_version};
-use std::{collections::HashMap, net::{SocketAddr, ToSocketAddrs}, path::PathBuf};
+use crate::{auth::{Auth, Realm, Scheme}, cache::CacheConfig, cors::Cors, errors::ErrorPages, headers::{HeaderAction, HeaderRules}, http::{Address, Backend, Status}, jwt::Jwt, log::{self, Category, Format, Level, LogConfig}, mime::{self, MimeTypes}, path::PathMatch, policy::FilePolicy, redirect::Redirects, rewrite::Rewrites, security::{Preset, SecurityHeaders}};
+use clap::{arg, Arg, ArgAction, crate_authors, crate_version};
+use std::{collections::HashMap, net::ToSocketAddrs, path::PathBuf, time::Duration};
 
 use self::file::ConfigFile;
 
@@ -19,24 +19,204 @@ macro_rules! set_if_default {
 }
 
 
+// Parse a byte size like "512", "64K" or "10M"
+fn parse_size(value: &str) -> error::Result<u64> {
+    let value = value.trim();
+    let (num, unit) = value.split_at(value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len()));
+
+    let multiplier = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        _ => return Err(error::Error::new(error::ErrorKind::InvalidValue, format!("Invalid size '{value}'")))
+    };
+
+    match num.parse::<u64>() {
+        Ok(n) => Ok(n * multiplier),
+        Err(_) => Err(error::Error::new(error::ErrorKind::InvalidValue, format!("Invalid size '{value}'")))
+    }
+}
+
+// Parse a duration like "30s", "15m", "12h" or "7d"
+fn parse_duration(value: &str) -> error::Result<Duration> {
+    let value = value.trim();
+    let (num, unit) = value.split_at(value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len()));
+
+    let multiplier = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        _ => return Err(error::Error::new(error::ErrorKind::InvalidValue, format!("Invalid duration '{value}'")))
+    };
+
+    match num.parse::<u64>() {
+        Ok(n) => Ok(Duration::from_secs(n * multiplier)),
+        Err(_) => Err(error::Error::new(error::ErrorKind::InvalidValue, format!("Invalid duration '{value}'")))
+    }
+}
+
+// Parse a comma-separated list of addresses, each with optional tags:
+// "0.0.0.0:80, [::]:80, localhost:8080 behind-tls, 127.0.0.1:8081 host=docs.local, unix:/run/ws.sock mode=660"
+fn parse_listeners(value: &str) -> error::Result<Vec<ListenConfig>> {
+    let mut listeners = vec![];
+
+    for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let mut parts = entry.split_whitespace();
+        let addr = parts.next().unwrap();
+        let (mut behind_tls, mut host, mut mode) = (false, None, None);
+
+        for tag in parts {
+            match tag.split_once('=') {
+                None if tag == "behind-tls" => behind_tls = true,
+                Some(("host", name)) => host = Some(name.to_ascii_lowercase()),
+                Some(("mode", m)) => mode = Some(u32::from_str_radix(m, 8)
+                    .map_err(|_| error::Error::new(error::ErrorKind::InvalidValue, format!("Invalid socket mode '{m}'")))?),
+                _ => return Err(error::Error::new(error::ErrorKind::InvalidValue, format!("Unknown address tag '{tag}'")))
+            }
+        }
+
+        let addrs = match addr.strip_prefix("unix:") {
+            Some(path) => vec![Address::Unix { path: path.into(), mode }],
+            None if mode.is_some() => return Err(error::Error::new(error::ErrorKind::InvalidValue, "Socket mode is only valid for unix: addresses")),
+
+            // Hostnames may resolve to several addresses, listen on all of them
+            None => addr.to_socket_addrs()?.map(Address::Tcp).collect()
+        };
+
+        for addr in addrs {
+            let listen = ListenConfig { addr, behind_tls, host: host.clone() };
+
+            if !listeners.contains(&listen) {
+                listeners.push(listen);
+            }
+        }
+    }
+
+    match listeners.is_empty() {
+        true => Err(error::Error::new(error::ErrorKind::AddrError, format!("No addresses in '{value}'"))),
+        false => Ok(listeners)
+    }
+}
+
+fn parse_level(value: &str) -> error::Result<Level> {
+    value.parse().map_err(|e: String| error::Error::new(error::ErrorKind::InvalidValue, e))
+}
+
+fn parse_preset(value: &str) -> error::Result<Preset> {
+    value.parse().map_err(|e: String| error::Error::new(error::ErrorKind::InvalidValue, e))
+}
+
+fn parse_backend(value: &str) -> error::Result<Backend> {
+    value.parse().map_err(|e: String| error::Error::new(error::ErrorKind::InvalidValue, e))
+}
+
+fn parse_index(value: &str) -> Vec<String> {
+    value.split(',').map(str::trim).filter(|i| !i.is_empty()).map(String::from).collect()
+}
+
+// A size threshold like "64K", or "off"
+fn parse_threshold(value: &str) -> error::Result<Option<u64>> {
+    match value {
+        "off" => Ok(None),
+        _ => parse_size(value).map(Some)
+    }
+}
+
+// "301", "308" or "off"
+fn parse_slash_redirect(value: &str) -> error::Result<Option<Status>> {
+    match value {
+        "301" => Ok(Some(Status::MovedPermanently)),
+        "308" => Ok(Some(Status::PermanentRedirect)),
+        "off" => Ok(None),
+        _ => Err(error::Error::new(error::ErrorKind::InvalidValue, format!("Invalid slash redirect '{value}', expected 301, 308 or off")))
+    }
+}
+
+
+// An address to listen on
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListenConfig {
+    pub addr: Address,
+    pub behind_tls: bool, // Plain HTTP from a TLS terminating proxy, so clients are on HTTPS
+    pub host: Option<String> // Host to serve when the Host header doesn't match one
+}
+
+
+// Everything served under a single Host name
 #[derive(Debug)]
-pub struct ServerConfig {
-    pub address: SocketAddr,
+pub struct HostConfig {
     pub dir: PathBuf,
-    pub redirects: HashMap<String, String>,
+    pub redirects: Redirects,
+    pub rewrites: Rewrites,
     pub ignored: PathMatch<()>,
     pub routes: PathMatch<PathBuf>,
+    pub errors: ErrorPages,
+    pub headers: HeaderRules,
+    pub cors: Cors,
+    pub security: SecurityHeaders,
+    pub auth: Auth,
+    pub jwt: Jwt,
+    pub files: FilePolicy,
+    pub mime: MimeTypes,
+    pub cache: Option<CacheConfig>, // In-memory file cache, off unless configured
+    pub sendfile: Option<u64>, // Smallest file sent with sendfile(2)
+    pub mmap: Option<u64>, // Smallest file sent from a memory mapping
+    pub index: Vec<String>, // Index files, tried in order
+    pub slash_redirect: Option<Status>,
+    pub spa: Option<PathBuf> // Fallback file for client-side routed apps
+}
+
+impl HostConfig {
+    fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        HostConfig {
+            dir: dir.into(),
+            redirects: Redirects::new(),
+            rewrites: Rewrites::new(),
+            ignored: PathMatch::new(),
+            routes: PathMatch::new(),
+            errors: ErrorPages::new(),
+            headers: HeaderRules::new(),
+            cors: Cors::new(),
+            security: SecurityHeaders::new(),
+            auth: Auth::new(),
+            jwt: Jwt::new(),
+            files: FilePolicy::new(),
+            mime: MimeTypes::new(),
+            cache: None,
+            sendfile: Some(64 << 10),
+            mmap: None,
+            index: vec!["index.html".into()],
+            slash_redirect: Some(Status::MovedPermanently),
+            spa: None
+        }
+    }
+}
+
+
+#[derive(Debug)]
+pub struct ServerConfig {
+    pub listeners: Vec<ListenConfig>,
+    pub site: HostConfig, // Default host
+    pub site_name: Option<String>,
+    pub hosts: HashMap<String, HostConfig>,
+    pub strict_hosts: bool,
+    pub backend: Backend,
+    pub log: LogConfig,
     no_config: bool
 }
 
 impl Default for ServerConfig {
     fn default() -> Self {
         ServerConfig {
-            address: "localhost:8080".to_socket_addrs().unwrap().next().unwrap(),
-            dir: "./src".into(),
-            redirects: HashMap::new(),
-            ignored: PathMatch::new(),
-            routes: PathMatch::new(),
+            listeners: parse_listeners("localhost:8080").unwrap(),
+            site: HostConfig::new("./src"),
+            site_name: None,
+            hosts: HashMap::new(),
+            strict_hosts: false,
+            backend: Backend::Polling,
+            log: LogConfig::default(),
             no_config: false
         }
     }
@@ -48,9 +228,14 @@ impl ServerConfig {
             .version(crate_version!())
             .author(crate_authors!())
             .args([
-                arg!(-a --address <ADDRESS> "Server host address"),
+                arg!(-a --address <ADDRESS> "Server host address(es), comma-separated").action(ArgAction::Append),
                 arg!(-d --dir <PATH> "Hosted directory"),
                 arg!(-n --noconfig "Don't attempt to load a server config from a file"),
+                arg!(-l --"log-level" <LEVEL> "Log level (off, error, warn, info, debug, trace)"),
+                arg!(-s --"strict-hosts" "Respond with 421 to requests for unknown hosts"),
+                arg!(--security <PRESET> "Security headers preset (off, basic, strict)"),
+                arg!(--backend <BACKEND> "I/O backend (polling, io-uring)"),
+                Arg::new("spa").long("spa").value_name("FILE").num_args(0..=1).default_missing_value("index.html").help("Serve FILE for HTML requests that don't match a file"),
                 Arg::new("ignore-file").long("ignore-file").short('i').value_name("URL").help("Ignore requests to a single file, send no response"),
                 Arg::new("ignore-dir").long("ignore-dir").short('I').value_name("URL").help("Ignore requests in a directory, send no response"),
                 Arg::new("redirect").long("redirect").short('r').value_names(["FROM", "TO"]).help("Redirect URLs"),
@@ -58,33 +243,54 @@ impl ServerConfig {
             ])
             .get_matches();
 
-        if let Some(addr) = cli.get_one::<String>("address") {
-            self.address = addr.parse()?;
+        if let Some(addrs) = cli.get_many::<String>("address") {
+            self.listeners.clear();
+
+            for addr in addrs {
+                self.listeners.extend(parse_listeners(addr)?);
+            }
         }
         if let Some(dir) = cli.get_one::<String>("dir") {
-            self.dir = dir.into();
+            self.site.dir = dir.into();
+        }
+        if let Some(preset) = cli.get_one::<String>("security") {
+            self.site.security.preset = parse_preset(preset)?;
+        }
+        if let Some(file) = cli.get_one::<String>("spa") {
+            self.site.spa = Some(file.into());
         }
         if cli.get_flag("noconfig") {
             self.no_config = true;
         }
+        if cli.get_flag("strict-hosts") {
+            self.strict_hosts = true;
+        }
+        if let Some(backend) = cli.get_one::<String>("backend") {
+            self.backend = parse_backend(backend)?;
+        }
+        if let Some(level) = cli.get_one::<String>("log-level") {
+            self.log.level = parse_level(level)?;
+        }
         if let Some(ignored) = cli.get_many::<String>("ignore-file") {
             for ignore in ignored {
-                self.ignored.add(ignore.into(), false);
+                self.site.ignored.add(ignore, false).map_err(|e| error::Error::new(error::ErrorKind::InvalidValue, e))?;
             }
         }
         if let Some(ignored) = cli.get_many::<String>("ignore-dir") {
             for ignore in ignored {
-                self.ignored.add(ignore.into(), true);
+                self.site.ignored.add(ignore, true).map_err(|e| error::Error::new(error::ErrorKind::InvalidValue, e))?;
             }
         }
         if let Some(redirects) = cli.get_occurrences::<String>("redirect") {
             for mut redir in redirects {
-                self.redirects.insert(redir.next().unwrap().into(), redir.next().unwrap().into());
+                self.site.redirects.add(redir.next().unwrap(), redir.next().unwrap())
+                    .map_err(|e| error::Error::new(error::ErrorKind::InvalidValue, e))?;
             }
         }
         if let Some(routes) = cli.get_occurrences::<String>("route") {
             for mut route in routes {
-                self.routes.add(route.next().unwrap().into(), route.next().unwrap().into());
+                self.site.routes.add(route.next().unwrap(), route.next().unwrap().into())
+                    .map_err(|e| error::Error::new(error::ErrorKind::InvalidValue, e))?;
             }
         }
 
@@ -102,45 +308,395 @@ impl ServerConfig {
         for section in cfg.sections() {
             match section.name.as_str() {
                 "global" => {
-                    if let Some(addr) = section.keys.get("address") {
-                        set_if_default!(self.address, addr.to_socket_addrs()?.next().unwrap(), default.address);
+                    if let Some(addr) = section.get("address") {
+                        set_if_default!(self.listeners, parse_listeners(addr)?, default.listeners);
+                    }
+                    if let Some(dir) = section.get("dir") {
+                        set_if_default!(self.site.dir, dir.into(), default.site.dir);
+                    }
+                    if let Some(file) = section.get("spa") {
+                        set_if_default!(self.site.spa, Some(file.into()), default.site.spa);
+                    }
+                    if let Some(preset) = section.get("security") {
+                        set_if_default!(self.site.security.preset, parse_preset(preset)?, default.site.security.preset);
+                    }
+                    if let Some(index) = section.get("index") {
+                        self.site.index = parse_index(index);
+                    }
+                    if let Some(redirect) = section.get("slash-redirect") {
+                        self.site.slash_redirect = parse_slash_redirect(redirect)?;
+                    }
+                    if let Some(min) = section.get("sendfile") {
+                        self.site.sendfile = parse_threshold(min)?;
+                    }
+                    if let Some(min) = section.get("mmap") {
+                        self.site.mmap = parse_threshold(min)?;
                     }
-                    if let Some(dir) = section.keys.get("dir") {
-                        set_if_default!(self.dir, dir.into(), default.dir);
+                    for option in ["symlinks", "hidden"] {
+                        if let Some(value) = section.get(option) {
+                            self.site.files.set("/", option, value).map_err(|e| error::Error::new(error::ErrorKind::InvalidValue, e))?;
+                        }
+                    }
+                    if let Some(name) = section.get("host") {
+                        self.site_name = Some(name.to_ascii_lowercase());
+                    }
+                    if let Some(strict) = section.get("strict-hosts") {
+                        self.strict_hosts |= strict == "true";
+                    }
+                    if let Some(backend) = section.get("backend") {
+                        set_if_default!(self.backend, parse_backend(backend)?, default.backend);
+                    }
+                },
+                "host" => {
+                    let host = self.host_mut(&section.arg)?;
+
+                    if let Some(dir) = section.get("dir") {
+                        host.dir = dir.into();
+                    }
+                    if let Some(file) = section.get("spa") {
+                        host.spa = Some(file.into());
+                    }
+                    if let Some(preset) = section.get("security") {
+                        host.security.preset = parse_preset(preset)?;
+                    }
+                    if let Some(index) = section.get("index") {
+                        host.index = parse_index(index);
+                    }
+                    if let Some(redirect) = section.get("slash-redirect") {
+                        host.slash_redirect = parse_slash_redirect(redirect)?;
+                    }
+                    if let Some(min) = section.get("sendfile") {
+                        host.sendfile = parse_threshold(min)?;
+                    }
+                    if let Some(min) = section.get("mmap") {
+                        host.mmap = parse_threshold(min)?;
+                    }
+                    for option in ["symlinks", "hidden"] {
+                        if let Some(value) = section.get(option) {
+                            host.files.set("/", option, value).map_err(|e| error::Error::new(error::ErrorKind::InvalidValue, e))?;
+                        }
                     }
                 },
                 "redirects" => {
+                    let host = self.host_mut(&section.arg)?;
+
                     for (from, to) in &section.keys {
-                        self.redirects.insert(from.clone(), to.clone());
+                        host.redirects.add(from, to).map_err(|e| error::Error::new(error::ErrorKind::InvalidValue, e))?;
+                    }
+                },
+                "rewrite" => {
+                    let host = self.host_mut(&section.arg)?;
+
+                    for (from, to) in &section.keys {
+                        host.rewrites.add(from, to).map_err(|e| error::Error::new(error::ErrorKind::InvalidValue, e))?;
                     }
                 },
                 "routes" => {
+                    let host = self.host_mut(&section.arg)?;
+
                     for (from, to) in &section.keys {
-                        self.routes.add(from.into(), to.into());
+                        host.routes.add(from, to.into()).map_err(|e| error::Error::new(error::ErrorKind::InvalidValue, e))?;
                     }
                 },
                 "ignore" => {
+                    let host = self.host_mut(&section.arg)?;
+
+                    // A trailing '/' ignores a directory & everything in it, otherwise a single file
                     for (path, _) in &section.keys {
-                        // TODO: Check if 'path' is a file or directory
-                        self.ignored.add(path.into(), false);
+                        host.ignored.add(path, path.ends_with('/')).map_err(|e| error::Error::new(error::ErrorKind::InvalidValue, e))?;
+                    }
+                },
+                "errors" => {
+                    let host = self.host_mut(&section.arg)?;
+
+                    for (key, file) in &section.keys {
+                        // Either "404" or "/path/prefix 404"
+                        let (prefix, code) = key.rsplit_once(char::is_whitespace).unwrap_or(("/", key));
+
+                        let code = match code.parse::<u16>() {
+                            Ok(code) if (400..600).contains(&code) => code,
+                            _ => return Err(error::Error::new(error::ErrorKind::InvalidKey, format!("Invalid error status '{code}'")))
+                        };
+
+                        host.errors.add(prefix.trim(), code, file);
+                    }
+                },
+                "headers" => {
+                    let host = self.host_mut(&section.arg)?;
+
+                    for (key, value) in &section.keys {
+                        // "<glob> <add|set|remove> <Header-Name>"
+                        let parts: Vec<_> = key.split_whitespace().collect();
+
+                        let (pattern, action, name) = match parts[..] {
+                            [pattern, action, name] => (pattern, action, name),
+                            _ => return Err(error::Error::new(error::ErrorKind::InvalidKey, format!("Expected '<path> <action> <header>', found '{key}'")))
+                        };
+                        let action = action.parse::<HeaderAction>()
+                            .map_err(|e| error::Error::new(error::ErrorKind::InvalidKey, e))?;
+
+                        host.headers.add(pattern, action, name, value)
+                            .map_err(|e| error::Error::new(error::ErrorKind::InvalidKey, e))?;
+                    }
+                },
+                "cors" => {
+                    let host = self.host_mut(&section.arg)?;
+
+                    // Either "option" or "/path/prefix option", set the "/" defaults first
+                    let (defaults, paths): (Vec<_>, Vec<_>) = section.keys.iter()
+                        .map(|(key, value)| match key.rsplit_once(char::is_whitespace) {
+                            Some((prefix, option)) => (prefix.trim(), option, value),
+                            None => ("/", key.as_str(), value)
+                        })
+                        .partition(|(prefix, _, _)| *prefix == "/");
+
+                    for (prefix, option, value) in defaults.into_iter().chain(paths) {
+                        host.cors.policy_mut(prefix).set(option, value)
+                            .map_err(|e| error::Error::new(error::ErrorKind::InvalidValue, e))?;
+                    }
+
+                    host.cors.check().map_err(|e| error::Error::new(error::ErrorKind::InvalidValue, e))?;
+                },
+                "files" => {
+                    let host = self.host_mut(&section.arg)?;
+
+                    // Either "option" or "/path/prefix option"
+                    for (key, value) in &section.keys {
+                        let (prefix, option) = key.rsplit_once(char::is_whitespace).unwrap_or(("/", key));
+
+                        host.files.set(prefix.trim(), option, value)
+                            .map_err(|e| error::Error::new(error::ErrorKind::InvalidValue, e))?;
+                    }
+                },
+                "mime" => {
+                    let host = self.host_mut(&section.arg)?;
+
+                    // "default", "system", "sniff", ".ext" or a path pattern
+                    for (key, value) in &section.keys {
+                        match key.as_str() {
+                            "default" => host.mime.default = match value.as_str() {
+                                "" | "none" => None,
+                                _ => Some(value.clone())
+                            },
+                            "system" => match value.as_str() {
+                                "false" => {},
+                                // The default file is optional, not every system has one
+                                "true" => if let Err(e) = host.mime.load_system(mime::SYSTEM_FILE) {
+                                    log::warning!(Server, "Can't Load System MIME Types: {e}"; "file" => mime::SYSTEM_FILE);
+                                },
+                                file => host.mime.load_system(file)
+                                    .map_err(|e| error::Error::new(error::ErrorKind::InvalidValue, format!("Can't read '{file}': {e}")))?
+                            },
+                            "sniff" => host.mime.sniff = value == "true",
+                            _ if key.starts_with(['/', '~']) => host.mime.force(key, value)
+                                .map_err(|e| error::Error::new(error::ErrorKind::InvalidKey, e))?,
+                            _ => host.mime.set(key, value.as_str())
+                        }
+                    }
+                },
+                "cache" => {
+                    let host = self.host_mut(&section.arg)?;
+                    let mut cache = CacheConfig::default();
+                    let mut enabled = true;
+
+                    for (key, value) in &section.keys {
+                        let invalid = || error::Error::new(error::ErrorKind::InvalidValue, format!("Invalid value '{value}' for '{key}'"));
+
+                        match key.as_str() {
+                            "enabled" => enabled = value == "true",
+                            "max-size" => cache.max_size = parse_size(value)?,
+                            "max-entries" => cache.max_entries = value.parse().map_err(|_| invalid())?,
+                            "max-file-size" => cache.max_file_size = parse_size(value)?,
+                            "invalidate" => cache.invalidate = value.parse().map_err(|e| error::Error::new(error::ErrorKind::InvalidValue, e))?,
+                            "compress" => cache.compress = value == "true",
+                            "warm" => cache.warm = value == "true",
+                            _ => return Err(error::Error::new(error::ErrorKind::InvalidKey, format!("Unknown cache option '{key}'")))
+                        }
+                    }
+
+                    host.cache = enabled.then_some(cache);
+                },
+                "security" => {
+                    let host = self.host_mut(&section.arg)?;
+
+                    for (header, value) in &section.keys {
+                        host.security.set(header, value);
+                    }
+                },
+                "auth" => {
+                    let host = self.host_mut(&section.arg)?;
+                    let mut realms: Vec<(&str, Realm, Option<&String>)> = vec![];
+
+                    // "/path/prefix realm", "/path/prefix scheme" & "/path/prefix file"
+                    for (key, value) in &section.keys {
+                        let (prefix, option) = key.rsplit_once(char::is_whitespace)
+                            .ok_or_else(|| error::Error::new(error::ErrorKind::InvalidKey, format!("Expected '<path> <option>', found '{key}'")))?;
+                        let prefix = prefix.trim();
+
+                        let index = match realms.iter().position(|(p, _, _)| *p == prefix) {
+                            Some(i) => i,
+                            None => {
+                                realms.push((prefix, Realm::new("Restricted", Scheme::Basic), None));
+                                realms.len() - 1
+                            }
+                        };
+                        let (_, realm, file) = &mut realms[index];
+
+                        match option {
+                            "realm" => realm.name = value.clone(),
+                            "scheme" => realm.scheme = value.parse().map_err(|e| error::Error::new(error::ErrorKind::InvalidValue, e))?,
+                            "file" => *file = Some(value),
+                            _ => return Err(error::Error::new(error::ErrorKind::InvalidKey, format!("Unknown auth option '{option}'")))
+                        }
+                    }
+
+                    for (prefix, mut realm, file) in realms {
+                        let file = file.ok_or_else(|| error::Error::new(error::ErrorKind::InvalidKey, format!("Missing credentials file for '{prefix}'")))?;
+
+                        realm.load_users(file)
+                            .map_err(|e| error::Error::new(error::ErrorKind::InvalidValue, format!("Can't read '{file}': {e}")))?;
+                        host.auth.add(prefix, realm);
+                    }
+                },
+                "jwt" => {
+                    let host = self.host_mut(&section.arg)?;
+
+                    // Either "option" or "/path/prefix option"
+                    for (key, value) in &section.keys {
+                        let res = match key.as_str() {
+                            "key" => host.jwt.add_key(value),
+                            "jwks" => host.jwt.load_jwks(value),
+                            "audience" => { host.jwt.set_audience(value); Ok(()) },
+                            "issuer" => { host.jwt.set_issuer(value); Ok(()) },
+                            "realm" => { host.jwt.realm = value.clone(); Ok(()) },
+                            "leeway" => { host.jwt.leeway = parse_duration(value)?.as_secs(); Ok(()) },
+                            _ => match key.rsplit_once(char::is_whitespace) {
+                                Some((prefix, option)) => host.jwt.set_rule(prefix.trim(), option, value),
+                                None => return Err(error::Error::new(error::ErrorKind::InvalidKey, format!("Unknown JWT option '{key}'")))
+                            }
+                        };
+
+                        res.map_err(|e| error::Error::new(error::ErrorKind::InvalidValue, e))?;
+                    }
+
+                    if !host.jwt.has_keys() {
+                        return Err(error::Error::new(error::ErrorKind::InvalidSection, "No JWT keys configured"));
                     }
                 },
                 "log" => {
-                    // TODO: Implement log levels / specific events
+                    for (key, value) in &section.keys {
+                        match key.as_str() {
+                            "level" => set_if_default!(self.log.level, parse_level(value)?, default.log.level),
+                            "format" => self.log.format = match value.as_str() {
+                                "text" => Format::Text,
+                                "json" => Format::Json,
+                                _ => return Err(error::Error::new(error::ErrorKind::InvalidValue, format!("Unknown log format '{value}'")))
+                            },
+                            "file" => self.log.file = Some(value.into()),
+                            "max-size" => self.log.max_size = Some(parse_size(value)?),
+                            "max-age" => self.log.max_age = Some(parse_duration(value)?),
+                            "keep" => self.log.keep = value.parse()
+                                .map_err(|_| error::Error::new(error::ErrorKind::InvalidValue, format!("Invalid keep count '{value}'")))?,
+                            _ => match key.parse::<Category>() {
+                                Ok(category) => { self.log.categories.insert(category, parse_level(value)?); },
+                                Err(e) => return Err(error::Error::new(error::ErrorKind::InvalidKey, e))
+                            }
+                        }
+                    }
                 },
                 _ => return Err(error::Error::new(error::ErrorKind::InvalidSection, "Unknown section"))
             }
         }
 
+        if let Some((name, _)) = self.hosts.iter().find(|(_, host)| host.dir.as_os_str().is_empty()) {
+            return Err(error::Error::new(error::ErrorKind::InvalidKey, format!("Missing 'dir' for host '{name}'")));
+        }
+
+        for host in self.listeners.iter().filter_map(|l| l.host.as_ref()) {
+            if !self.hosts.contains_key(host) && self.site_name.as_ref() != Some(host) {
+                return Err(error::Error::new(error::ErrorKind::InvalidValue, format!("Address tagged with unknown host '{host}'")));
+            }
+        }
+
         Ok(self)
     }
 
+    // Get the config for a named host section, or the default host if unnamed
+    fn host_mut(&mut self, name: &Option<String>) -> error::Result<&mut HostConfig> {
+        match name {
+            Some(name) if name.is_empty() => Err(error::Error::new(error::ErrorKind::InvalidSection, "Empty host name")),
+            Some(name) => Ok(self.hosts.entry(name.to_ascii_lowercase()).or_insert_with(|| HostConfig::new(""))),
+            None => Ok(&mut self.site)
+        }
+    }
+
     pub fn load() -> Result<Self, Box<dyn std::error::Error>> {
         let cfg = ServerConfig::default().load_cli()?;
 
-        match cfg.no_config {
-            true => Ok(cfg),
-            false => Ok(cfg.load_file()?)
+        let cfg = match cfg.no_config {
+            true => cfg,
+            false => cfg.load_file()?
+        };
+
+        for host in std::iter::once(&cfg.site).chain(cfg.hosts.values()) {
+            host.redirects.check_loops().map_err(|e| error::Error::new(error::ErrorKind::InvalidValue, e))?;
         }
+
+        Ok(cfg)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{*, error::ErrorKind};
+
+    fn tcp(addr: &str) -> Address {
+        Address::Tcp(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn listener_tags() {
+        let listeners = parse_listeners("127.0.0.1:80, [::1]:8443 behind-tls host=Docs.Local, unix:/run/ws.sock mode=660").unwrap();
+
+        assert_eq!(listeners, vec![
+            ListenConfig { addr: tcp("127.0.0.1:80"), behind_tls: false, host: None },
+            ListenConfig { addr: tcp("[::1]:8443"), behind_tls: true, host: Some("docs.local".into()) },
+            ListenConfig { addr: Address::Unix { path: "/run/ws.sock".into(), mode: Some(0o660) }, behind_tls: false, host: None }
+        ]);
+
+        assert_eq!(parse_listeners("127.0.0.1:80 tls").unwrap_err().kind, ErrorKind::InvalidValue);
+        assert_eq!(parse_listeners("unix:/run/ws.sock mode=999").unwrap_err().kind, ErrorKind::InvalidValue);
+        assert_eq!(parse_listeners(" , ").unwrap_err().kind, ErrorKind::AddrError);
+    }
+
+    #[test]
+    fn listener_modes() {
+        // A mode only applies to socket files
+        let err = parse_listeners("127.0.0.1:80 mode=600").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::InvalidValue);
+        assert!(err.message.contains("unix:"));
+    }
+
+    #[test]
+    fn duplicate_listeners() {
+        let listeners = parse_listeners("127.0.0.1:80, 127.0.0.1:80, 127.0.0.1:80 host=a").unwrap();
+
+        assert_eq!(listeners, vec![
+            ListenConfig { addr: tcp("127.0.0.1:80"), behind_tls: false, host: None },
+            ListenConfig { addr: tcp("127.0.0.1:80"), behind_tls: false, host: Some("a".into()) }
+        ]);
+    }
+
+    #[test]
+    fn hostname_listeners() {
+        let mut expected: Vec<_> = "localhost:8080".to_socket_addrs().unwrap().map(Address::Tcp).collect();
+        expected.dedup();
+
+        // Every resolved address keeps the tags
+        let listeners = parse_listeners("localhost:8080 behind-tls host=a").unwrap();
+        assert!(!listeners.is_empty());
+        assert!(listeners.iter().all(|l| l.behind_tls && l.host.as_deref() == Some("a")));
+        assert_eq!(listeners.into_iter().map(|l| l.addr).collect::<Vec<_>>(), expected);
     }
 }
@@ -0,0 +1,233 @@
+// Cross-Origin Resource Sharing
+
+use crate::{path::{compile_glob, Prefixes}, http::{self, response::{Response, ResponseBuilder}, Status}};
+use httparse::Request;
+use regex::Regex;
+
+
+#[derive(Debug, Clone)]
+pub enum Origin {
+    Any,
+    Exact(String),
+    Wildcard(Regex, bool), // Glob, e.g. "*.example.com" or "https://*.example.com", & whether it has a scheme
+    Regex(Regex)
+}
+
+impl Origin {
+    // Parse "*", "https://exact.com", "*.example.com" or "~^regex$"
+    pub fn parse(value: &str) -> Result<Self, String> {
+        if value == "*" {
+            Ok(Origin::Any)
+        }
+        else if let Some(re) = value.strip_prefix('~') {
+            Regex::new(re).map(Origin::Regex).map_err(|e| format!("Invalid origin regex '{re}': {e}"))
+        }
+        else if value.contains(['*', '?']) {
+            let pattern = value.to_ascii_lowercase();
+            Ok(Origin::Wildcard(compile_glob(&pattern)?, pattern.contains("://")))
+        }
+        else {
+            Ok(Origin::Exact(value.trim_end_matches('/').to_ascii_lowercase()))
+        }
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        let origin = origin.to_ascii_lowercase();
+
+        match self {
+            Origin::Any => true,
+            Origin::Exact(o) => *o == origin,
+            Origin::Regex(re) => re.is_match(&origin),
+            Origin::Wildcard(pattern, true) => pattern.is_match(&origin),
+            Origin::Wildcard(pattern, false) => origin.split_once("://").is_some_and(|(_, host)| pattern.is_match(host))
+        }
+    }
+}
+
+
+#[derive(Debug, Clone)]
+pub struct CorsPolicy {
+    pub origins: Vec<Origin>,
+    pub methods: Vec<String>,
+    pub headers: Vec<String>, // "*" allows any requested header
+    pub expose: Vec<String>,
+    pub credentials: bool,
+    pub max_age: Option<u64>
+}
+
+impl Default for CorsPolicy {
+    fn default() -> Self {
+        CorsPolicy {
+            origins: vec![],
+            methods: vec!["GET".into(), "HEAD".into()],
+            headers: vec![],
+            expose: vec![],
+            credentials: false,
+            max_age: None
+        }
+    }
+}
+
+impl CorsPolicy {
+    // Set an option from the [cors] section
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let list = || value.split(',').map(str::trim).filter(|v| !v.is_empty());
+
+        match key {
+            "origins" => self.origins = list().map(Origin::parse).collect::<Result<_, _>>()?,
+            "methods" => self.methods = list().map(str::to_ascii_uppercase).collect(),
+            "headers" => self.headers = list().map(str::to_ascii_lowercase).collect(),
+            "expose" => self.expose = list().map(String::from).collect(),
+            "credentials" => self.credentials = value == "true",
+            "max-age" => self.max_age = Some(value.parse().map_err(|_| format!("Invalid max-age '{value}'"))?),
+            _ => return Err(format!("Unknown CORS option '{key}'"))
+        }
+
+        Ok(())
+    }
+
+    fn allows_origin(&self, origin: &str) -> bool {
+        self.origins.iter().any(|o| o.matches(origin))
+    }
+
+    fn allows_any_origin(&self) -> bool {
+        self.origins.iter().any(|o| matches!(o, Origin::Any))
+    }
+
+    fn allows_header(&self, header: &str) -> bool {
+        self.headers.iter().any(|h| h == "*" || h.eq_ignore_ascii_case(header))
+    }
+
+    // Value for Access-Control-Allow-Origin, "*" is only allowed without credentials
+    fn allow_origin<'a>(&self, origin: &'a str) -> &'a str {
+        match self.allows_any_origin() {
+            true => "*",
+            false => origin
+        }
+    }
+}
+
+
+#[derive(Debug)]
+pub struct Cors {
+    policies: Prefixes<CorsPolicy>
+}
+
+impl Cors {
+    pub fn new() -> Self {
+        Cors { policies: Prefixes::new() }
+    }
+
+    // Get the policy for a path prefix, starting from the "/" policy if it's new
+    pub fn policy_mut(&mut self, prefix: &str) -> &mut CorsPolicy {
+        let base = self.policies.get("/").cloned().unwrap_or_default();
+        self.policies.entry(prefix, || base)
+    }
+
+    // Echoing any origin with credentials would let every site make credentialed requests
+    pub fn check(&self) -> Result<(), String> {
+        match self.policies.iter().find(|(_, p)| p.credentials && p.allows_any_origin()) {
+            Some((prefix, _)) => Err(format!("CORS credentials need explicit origins, not '*' (for '{prefix}')")),
+            None => Ok(())
+        }
+    }
+
+    // Answer a preflight request, or None if 'req' isn't one
+    pub fn preflight(&self, path: &str, req: &Request) -> Option<Response> {
+        let origin = http::header(req, "Origin")?;
+        let method = http::header(req, "Access-Control-Request-Method")?;
+        let policy = self.policies.get(path)?;
+
+        let requested: Vec<_> = http::header(req, "Access-Control-Request-Headers")
+            .unwrap_or("")
+            .split(',')
+            .map(str::trim)
+            .filter(|h| !h.is_empty())
+            .collect();
+
+        let allowed = policy.allows_origin(origin)
+            && policy.methods.iter().any(|m| m.eq_ignore_ascii_case(method))
+            && requested.iter().all(|h| policy.allows_header(h));
+
+        let mut res = ResponseBuilder::new()
+            .header("Vary", "Origin, Access-Control-Request-Method, Access-Control-Request-Headers");
+
+        if !allowed {
+            return Some(res.status(Status::Forbidden).into_response());
+        }
+
+        res = res.status(Status::NoContent)
+            .header("Access-Control-Allow-Origin", policy.allow_origin(origin))
+            .header("Access-Control-Allow-Methods", policy.methods.join(", "));
+
+        if !requested.is_empty() {
+            res = res.header("Access-Control-Allow-Headers", requested.join(", "));
+        }
+        if policy.credentials {
+            res = res.header("Access-Control-Allow-Credentials", "true");
+        }
+        if let Some(max_age) = policy.max_age {
+            res = res.header("Access-Control-Max-Age", max_age.to_string());
+        }
+
+        Some(res.into_response())
+    }
+
+    // Add CORS headers to a response for an allowed origin
+    pub fn apply(&self, path: &str, req: &Request, res: &mut Response) {
+        let policy = match self.policies.get(path) {
+            Some(p) => p,
+            None => return
+        };
+
+        // Responses differ by origin unless every origin gets "*"
+        if policy.allow_origin("") != "*" {
+            res.add_header("Vary", "Origin");
+        }
+
+        let origin = match http::header(req, "Origin") {
+            Some(o) if policy.allows_origin(o) => o,
+            _ => return
+        };
+
+        res.set_header("Access-Control-Allow-Origin", policy.allow_origin(origin));
+
+        if policy.credentials {
+            res.set_header("Access-Control-Allow-Credentials", "true");
+        }
+        if !policy.expose.is_empty() {
+            res.set_header("Access-Control-Expose-Headers", policy.expose.join(", "));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcard_origins() {
+        let host = Origin::parse("*.example.com").unwrap();
+        assert!(host.matches("https://a.example.com"));
+        assert!(host.matches("http://A.Example.com"));
+        assert!(!host.matches("https://example.com.evil.net"));
+
+        let scheme = Origin::parse("https://*.example.com").unwrap();
+        assert!(scheme.matches("https://a.example.com"));
+        assert!(!scheme.matches("http://a.example.com"));
+    }
+
+    #[test]
+    fn any_origin_with_credentials() {
+        let mut cors = Cors::new();
+        cors.policy_mut("/").set("origins", "*").unwrap();
+        assert!(cors.check().is_ok());
+
+        cors.policy_mut("/api").set("credentials", "true").unwrap();
+        assert!(cors.check().is_err());
+
+        cors.policy_mut("/api").set("origins", "https://example.com").unwrap();
+        assert!(cors.check().is_ok());
+    }
+}
@@ -0,0 +1,52 @@
+// Custom error pages
+
+use crate::{log, path::Prefixes, http::response::Response};
+use std::{collections::HashMap, fs, path::{Path, PathBuf}};
+
+
+#[derive(Debug)]
+pub struct ErrorPages {
+    dir: PathBuf,
+    pages: Prefixes<HashMap<u16, PathBuf>> // Page files by status, relative to the served directory
+}
+
+impl ErrorPages {
+    pub fn new() -> Self {
+        ErrorPages {
+            dir: PathBuf::new(),
+            pages: Prefixes::new()
+        }
+    }
+
+    pub fn add<P: Into<PathBuf>>(&mut self, prefix: &str, code: u16, file: P) {
+        self.pages.entry(prefix, HashMap::new).insert(code, file.into());
+    }
+
+    // Set the directory page files are read from
+    pub fn with_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.dir = dir.as_ref().to_owned();
+        self
+    }
+
+    // Replace the body of an error response with its configured page, keeping the status
+    pub fn apply(&self, path: &str, mut res: Response) -> Response {
+        let code = res.status().code();
+
+        if code < 400 {
+            return res;
+        }
+
+        // The page from the longest prefix with one for this status
+        if let Some(file) = self.pages.find(path, |pages| pages.get(&code)) {
+            match fs::read(self.dir.join(file.strip_prefix("/").unwrap_or(file))) {
+                Ok(data) => {
+                    res.set_header("Content-Type", "text/html; charset=utf-8");
+                    res.set_body(data);
+                },
+                Err(e) => log::error!(Serve, "Error Reading Error Page: {e}"; "code" => code, "file" => file.display())
+            }
+        }
+
+        res
+    }
+}
@@ -0,0 +1,68 @@
+// Custom response headers for paths
+
+use crate::{http::response::Response, path::compile_glob};
+use regex::Regex;
+use std::str::FromStr;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderAction {
+    Add,    // Keep existing values
+    Set,    // Replace existing values
+    Remove
+}
+
+impl FromStr for HeaderAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "add" => Ok(HeaderAction::Add),
+            "set" => Ok(HeaderAction::Set),
+            "remove" => Ok(HeaderAction::Remove),
+            _ => Err(format!("Unknown header action '{s}'"))
+        }
+    }
+}
+
+
+#[derive(Debug)]
+struct HeaderRule {
+    pattern: Regex,
+    action: HeaderAction,
+    name: String,
+    value: String
+}
+
+
+// Rules are applied in order, so later rules override earlier ones
+#[derive(Debug)]
+pub struct HeaderRules(Vec<HeaderRule>);
+
+impl HeaderRules {
+    pub fn new() -> Self {
+        HeaderRules(vec![])
+    }
+
+    // Add a rule for a plain path, a glob like "/assets/**/*.css", or a regex starting with '~'
+    pub fn add<S: Into<String>>(&mut self, pattern: &str, action: HeaderAction, name: S, value: S) -> Result<(), String> {
+        self.0.push(HeaderRule {
+            pattern: compile_glob(pattern)?,
+            action,
+            name: name.into(),
+            value: value.into()
+        });
+
+        Ok(())
+    }
+
+    pub fn apply(&self, path: &str, res: &mut Response) {
+        for rule in self.0.iter().filter(|r| r.pattern.is_match(path)) {
+            match rule.action {
+                HeaderAction::Add => res.add_header(&rule.name, rule.value.as_str()),
+                HeaderAction::Set => res.set_header(&rule.name, rule.value.as_str()),
+                HeaderAction::Remove => res.remove_header(&rule.name)
+            }
+        }
+    }
+}
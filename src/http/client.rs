@@ -1,13 +1,49 @@
 // Manage connected clients
 
+use super::{writer::Writer, Connection, Stream};
 use polling::{Poller, Event};
-use std::{net::TcpStream, io, collections::{HashMap, VecDeque}, time::Duration};
+use std::{io, collections::HashMap, time::Duration};
+
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// Time a slow client gets to accept more of a response, slow readers only
+// become writable again once a large part of the send buffer is free
+const SEND_TIMEOUT: Duration = Duration::from_secs(60);
+
+
+#[derive(Debug)]
+pub struct Client {
+    pub stream: Stream,
+    pub conn: Connection,
+    pub writer: Writer,
+    writing: bool, // Waiting for the socket to become writable
+    timeout: Duration
+}
+
+impl Client {
+    // Send queued responses, waiting for writable events if the socket is full
+    pub fn flush(&mut self, key: usize, poller: &Poller) -> io::Result<()> {
+        let done = self.writer.flush(&mut self.stream)?;
+
+        if self.writing || !done {
+            self.timeout = if done { TIMEOUT } else { SEND_TIMEOUT };
+        }
+
+        if done == self.writing {
+            let interest = if done { Event::readable(key) } else { Event::all(key) };
+            poller.modify_with_mode(&self.stream, interest, polling::PollMode::Level)?;
+            self.writing = !done;
+        }
+
+        Ok(())
+    }
+}
 
 
 #[derive(Debug)]
 pub struct Clients {
-    clients: HashMap<usize, (TcpStream, Duration)>,
-    timeouts: VecDeque<usize>, // Sorted timeouts
+    clients: HashMap<usize, Client>,
     avail: Vec<usize>
 }
 
@@ -15,58 +51,46 @@ impl Clients {
     pub fn new() -> Self {
         Clients {
             clients: HashMap::new(),
-            timeouts: VecDeque::with_capacity(512),
             avail: (1..=512).rev().collect()
         }
     }
 
-    pub fn add(&mut self, stream: TcpStream, poller: &Poller) -> io::Result<usize> {
+    pub fn add(&mut self, stream: Stream, conn: Connection, poller: &Poller) -> io::Result<usize> {
         let key = self.avail.pop()
-            .ok_or(io::Error::new(io::ErrorKind::Other, "Client Limit Reached"))?;
+            .ok_or(io::Error::other("Client Limit Reached"))?;
 
         if let Err(e) = poller.add_with_mode(&stream, Event::readable(key), polling::PollMode::Level) {
             self.avail.push(key); // Re-add the key
             return Err(e);
         }
 
-        self.clients.insert(key, (stream, Duration::from_secs(5)));
-        self.timeouts.push_back(key);
+        self.clients.insert(key, Client { stream, conn, writer: Writer::default(), writing: false, timeout: TIMEOUT });
         Ok(key)
     }
 
-    fn remove_timeout(&mut self, key: usize) {
-        for (i, t) in self.timeouts.iter().enumerate() {
-            if t == &key {
-                self.timeouts.remove(i);
-                break;
-            }
-        }
-    }
+    pub fn remove(&mut self, key: usize, poller: &Poller) -> io::Result<Stream> {
+        let client = self.clients.remove(&key)
+            .ok_or(io::Error::other(format!("Client {key} Does Not Exist")))?;
 
-    pub fn remove(&mut self, key: usize, poller: &Poller) -> io::Result<TcpStream> {
-        let (stream, _) = self.clients.remove(&key)
-            .ok_or(io::Error::new(io::ErrorKind::Other, format!("Client {key} Does Not Exist")))?;
-
-        poller.delete(&stream)?;
+        poller.delete(&client.stream)?;
         self.avail.push(key);
-        self.remove_timeout(key);
-        Ok(stream)
+        Ok(client.stream)
     }
 
-    pub fn get(&mut self, key: usize) -> Option<&mut TcpStream> {
-        self.clients.get_mut(&key).map(|(stream, _)| stream)
+    pub fn get(&mut self, key: usize) -> Option<&mut Client> {
+        self.clients.get_mut(&key)
     }
 
     // Subtract a duration from all clients
     pub fn sub_time(&mut self, time: Duration) {
         for cl in self.clients.values_mut() {
-            cl.1 = cl.1.saturating_sub(time);
+            cl.timeout = cl.timeout.saturating_sub(time);
         }
     }
 
     // Get the next (smallest) timeout
     pub fn next_timeout(&self) -> Option<Duration> {
-        Some(self.clients[self.timeouts.front()?].1)
+        self.clients.values().map(|cl| cl.timeout).min()
     }
 
     // Remove all clients with an expired timeout
@@ -74,7 +98,7 @@ impl Clients {
         let mut rem_keys = vec![];
 
         self.clients.retain(|key, cl| {
-            if cl.1.is_zero() {
+            if cl.timeout.is_zero() {
                 rem_keys.push(*key);
                 false
             }
@@ -83,9 +107,6 @@ impl Clients {
             }
         });
 
-        for key in rem_keys {
-            self.avail.push(key);
-            self.remove_timeout(key);
-        }
+        self.avail.extend(rem_keys);
     }
 }
@@ -1,92 +1,289 @@
 mod client;
 pub mod response;
+mod socket;
 mod status;
+#[cfg(test)]
+pub mod testing;
+#[cfg(feature = "io-uring")]
+mod uring;
+mod url;
+mod writer;
 
-use client::Clients;
-use response::{Response, ResponseBuilder};
+use crate::{log, signal, systemd};
+use client::{Client, Clients};
+use response::Response;
+use socket::{Listener, Stream};
+pub use socket::{Address, Peer};
 pub use status::Status;
+pub use url::Url;
 use httparse::{Request, EMPTY_HEADER};
 use polling::{Poller, Event, PollMode};
-use std::{net::{TcpListener, SocketAddr, TcpStream}, io::{self, Read, Write}, time::Instant, rc::Rc};
+use std::{io::{self, Read}, os::unix::io::RawFd, str::FromStr, time::{Duration, Instant}, rc::Rc};
+
+
+pub type Handler<T, R> = Box<dyn Fn(Rc<T>, &Connection, Request) -> Option<R>>;
+
+
+// Get a request header's value by name, ignoring case
+pub fn header<'a>(req: &Request<'_, 'a>, name: &str) -> Option<&'a str> {
+    req.headers.iter()
+        .find(|h| h.name.eq_ignore_ascii_case(name))
+        .and_then(|h| std::str::from_utf8(h.value).ok())
+}
+
+
+// Poller keys at or above this belong to listeners, client keys are below it
+const LISTENER_KEY: usize = 1 << 16;
+
+
+// Details about the connection a request arrived on
+#[derive(Debug, Clone)]
+pub struct Connection {
+    pub listener: usize, // Index of the address given to Server::bind
+    pub peer: Peer
+}
+
+
+// How the server waits for & performs socket I/O
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Polling,
+    #[cfg(feature = "io-uring")]
+    IoUring
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "polling" => Ok(Backend::Polling),
+            #[cfg(feature = "io-uring")]
+            "io-uring" => Ok(Backend::IoUring),
+            #[cfg(not(feature = "io-uring"))]
+            "io-uring" => Err("The io-uring backend needs ws built with the 'io-uring' feature".into()),
+            _ => Err(format!("Unknown backend '{s}'"))
+        }
+    }
+}
+
+
+// Whether SIGINT or SIGTERM was received, telling systemd we're stopping if so
+fn shutdown_requested() -> bool {
+    if signal::take(libc::SIGINT) || signal::take(libc::SIGTERM) {
+        log::info!(Server, "Shutting Down");
+        systemd::notify("STOPPING=1");
+        return true;
+    }
+
+    false
+}
+
+// Notify systemd's watchdog once its interval has passed
+fn notify_watchdog(watchdog: &mut Option<(Duration, Instant)>) {
+    if let Some((interval, last)) = *watchdog {
+        if last.elapsed() >= interval {
+            systemd::notify("WATCHDOG=1");
+            *watchdog = Some((interval, Instant::now()));
+        }
+    }
+}
+
+// Time until the watchdog needs notifying, or a client times out
+fn next_wakeup(timeout: Option<Duration>, watchdog: Option<(Duration, Instant)>) -> Option<Duration> {
+    let watchdog = watchdog.map(|(interval, last)| interval.saturating_sub(last.elapsed()));
+
+    match (timeout, watchdog) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b)
+    }
+}
+
+// Handle one request, logging it & returning the response to send, if any
+fn respond<R: Into<Response>, T>(app: &Handler<T, R>, state: &Rc<T>, conn: &Connection, key: usize, data: &[u8]) -> Option<Response> {
+    let mut headers = [EMPTY_HEADER; 24];
+    let mut req = Request::new(&mut headers);
+
+    if let Ok(httparse::Status::Complete(_)) = req.parse(data) {
+        let (method, path) = (req.method.unwrap_or("-"), req.path.unwrap_or("-"));
+
+        match app(state.clone(), conn, req) {
+            Some(builder) => {
+                let res = builder.into();
+                log::info!(Access, "{method} {path}"; "peer" => conn.peer, "user" => res.user().unwrap_or("-"), "status" => res.status().code(), "bytes" => res.body_len());
+                Some(res)
+            },
+            None => {
+                log::info!(Access, "{method} {path}"; "peer" => conn.peer, "status" => "ignored");
+                None
+            }
+        }
+    }
+    else {
+        log::debug!(Client, "Malformed Request"; "client" => key);
+        Some(Response::error(Status::BadRequest))
+    }
+}
 
 
 pub struct Server {
-    listener: TcpListener,
+    listeners: Vec<Listener>,
     poller: Poller,
-    clients: Clients
+    clients: Clients,
+    backend: Backend
 }
 
 impl Server {
-    pub fn bind(addr: SocketAddr) -> io::Result<Self> {
+    pub fn bind(addrs: &[Address]) -> io::Result<Self> {
+        let mut listeners = Vec::with_capacity(addrs.len());
+
+        for addr in addrs {
+            let listener = Listener::bind(addr)
+                .map_err(|e| io::Error::new(e.kind(), format!("Failed to bind {addr}: {e}")))?;
+
+            listeners.push(listener);
+        }
+
+        Self::with_listeners(listeners)
+    }
+
+    // Serve on sockets that are already listening, e.g. from systemd socket activation
+    pub fn from_fds(fds: &[RawFd]) -> io::Result<Self> {
+        let listeners = fds.iter()
+            .map(|fd| Listener::from_fd(*fd))
+            .collect::<io::Result<_>>()?;
+
+        Self::with_listeners(listeners)
+    }
+
+    fn with_listeners(listeners: Vec<Listener>) -> io::Result<Self> {
         Ok(Server {
-            listener: TcpListener::bind(addr)?,
+            listeners,
             poller: Poller::new()?,
-            clients: Clients::new()
+            clients: Clients::new(),
+            backend: Backend::Polling
         })
     }
 
-    pub fn serve_with_state<R: Into<Response>, T>(&mut self, app: Box<dyn Fn(Rc<T>, Request) -> Option<R>>, state: Rc<T>) -> io::Result<()> {
-        self.poller.add_with_mode(&self.listener, Event::readable(0), PollMode::Level)?;
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    // Addresses of all listeners, in the order they were given
+    pub fn addresses(&self) -> io::Result<Vec<Address>> {
+        self.listeners.iter().map(Listener::address).collect()
+    }
+
+    // Serve requests until SIGINT or SIGTERM is received
+    pub fn serve_with_state<R: Into<Response>, T>(&mut self, app: Handler<T, R>, state: Rc<T>) -> io::Result<()> {
+        signal::listen(libc::SIGINT)?;
+        signal::listen(libc::SIGTERM)?;
+
+        match self.backend {
+            Backend::Polling => self.serve_polling(app, state),
+            #[cfg(feature = "io-uring")]
+            Backend::IoUring => uring::serve(&self.listeners, app, state)
+        }
+    }
+
+    fn serve_polling<R: Into<Response>, T>(&mut self, app: Handler<T, R>, state: Rc<T>) -> io::Result<()> {
+        for (i, listener) in self.listeners.iter().enumerate() {
+            self.poller.add_with_mode(listener, Event::readable(LISTENER_KEY + i), PollMode::Level)?;
+        }
 
         let mut events = Vec::with_capacity(20);
         let mut prev_time = Instant::now();
+        let mut watchdog = systemd::watchdog_interval().map(|interval| (interval, Instant::now()));
+
+        systemd::notify("READY=1");
 
         loop {
+            if shutdown_requested() {
+                return Ok(());
+            }
+
+            notify_watchdog(&mut watchdog);
+
             events.clear();
-            self.poller.wait(&mut events, self.clients.next_timeout())?;
+            match self.poller.wait(&mut events, next_wakeup(self.clients.next_timeout(), watchdog)) {
+                Ok(_) => {},
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue, // Signal received
+                Err(e) => return Err(e)
+            }
 
             let now = Instant::now();
             self.clients.sub_time(now.duration_since(prev_time));
             prev_time = now;
 
-            if events.len() == 0 {
+            if events.is_empty() {
                 self.clients.remove_timed_out();
                 continue;
             }
 
             for ev in &events {
-                if ev.key == 0 {
-                    let (stream, _) = self.listener.accept()?;
+                if ev.key >= LISTENER_KEY {
+                    let listener = ev.key - LISTENER_KEY;
 
-                    if let Err(e) = self.clients.add(stream, &self.poller) {
-                        eprintln!("Error Adding Client: {e}");
+                    let (stream, peer) = match self.listeners[listener].accept() {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            log::error!(Server, "Error Accepting Client: {e}"; "listener" => listener);
+                            continue;
+                        }
+                    };
+
+                    match self.clients.add(stream, Connection { listener, peer }, &self.poller) {
+                        Ok(key) => log::debug!(Client, "Client Connected"; "client" => key, "peer" => peer, "listener" => listener),
+                        Err(e) => log::error!(Client, "Error Adding Client: {e}"; "peer" => peer)
                     }
                 }
-                else if let Some(mut stream) = self.clients.get(ev.key) {
-                    let mut buf = [0u8; 2048];
-                    let bytes_read = stream.read(&mut buf)?;
+                else if let Some(client) = self.clients.get(ev.key) {
+                    let mut disconnect = false;
+
+                    if ev.readable {
+                        let mut buf = [0u8; 2048];
 
-                    if bytes_read == 0 {
-                        if let Err(e) = self.clients.remove(ev.key, &self.poller) {
-                            eprintln!("Error Removing Client: {e}");
+                        match client.stream.read(&mut buf) {
+                            Ok(0) => disconnect = true,
+                            Ok(bytes_read) => {
+                                if let Some(res) = respond(&app, &state, &client.conn, ev.key, &buf[..bytes_read]) {
+                                    Self::queue(client, res);
+                                }
+                            },
+                            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted) => {},
+                            Err(_) => disconnect = true
                         }
-                        continue;
                     }
 
-                    let mut headers = [EMPTY_HEADER; 24];
-                    let mut req = Request::new(&mut headers);
-
-                    if let Ok(httparse::Status::Complete(_)) = req.parse(&buf[0..bytes_read]) {
-                        if let Some(builder) = app(state.clone(), req) {
-                            match builder.into().to_bytes() {
-                                Ok(ref res) => stream.write_all(res)?,
-                                Err(_) => Self::send_error(&mut stream, Status::InternalServerError)?
-                            }
+                    if !disconnect {
+                        if let Err(e) = client.flush(ev.key, &self.poller) {
+                            log::debug!(Client, "Error Sending Response: {e}"; "client" => ev.key);
+                            disconnect = true;
                         }
                     }
-                    else {
-                        Self::send_error(&mut stream, Status::BadRequest)?;
+
+                    if disconnect {
+                        match self.clients.remove(ev.key, &self.poller) {
+                            Ok(_) => log::debug!(Client, "Client Disconnected"; "client" => ev.key),
+                            Err(e) => log::error!(Client, "Error Removing Client: {e}"; "client" => ev.key)
+                        }
                     }
                 }
             }
         }
     }
 
-    fn send_error(stream: &mut TcpStream, status: Status) -> io::Result<()> {
-        let res = ResponseBuilder::new()
-            .status(status)
-            .into_response();
+    fn queue(client: &mut Client, res: Response) {
+        match res.into_parts() {
+            Ok((head, file)) => client.writer.push(head, file),
+            Err(_) => Self::send_error(client, Status::InternalServerError)
+        }
+    }
 
-        stream.write_all(&res.to_bytes()?)
+    fn send_error(client: &mut Client, status: Status) {
+        if let Ok(bytes) = Response::error(status).into_bytes() {
+            client.writer.push(bytes, None);
+        }
     }
 }
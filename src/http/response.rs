@@ -1,7 +1,8 @@
 // Response builder & sender
 
 use super::Status;
-use std::{collections::HashMap, io::{self, Write}};
+use memmap2::Mmap;
+use std::{fs, io::{self, Read, Write}, ops::Deref, rc::Rc};
 
 
 fn title_case(string: &str) -> String {
@@ -26,12 +27,65 @@ fn title_case(string: &str) -> String {
 }
 
 
+// Header list allowing repeated names, e.g. for multiple 'Link' headers
+#[derive(Debug, Default)]
+struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.iter().find(|(k, _)| k.eq_ignore_ascii_case(key)).map(|(_, v)| v.as_str())
+    }
+
+    // Replace all values for a header
+    fn set(&mut self, key: &str, value: String) {
+        self.remove(key);
+        self.add(key, value);
+    }
+
+    fn add(&mut self, key: &str, value: String) {
+        self.0.push((title_case(key), value));
+    }
+
+    fn remove(&mut self, key: &str) {
+        self.0.retain(|(k, _)| !k.eq_ignore_ascii_case(key));
+    }
+}
+
+
+// A file body, sent from its descriptor or a mapping without being read into memory
+#[derive(Debug)]
+pub struct FileBody {
+    pub file: Rc<fs::File>,
+    pub len: u64,
+    pub map: Option<Rc<Mmap>> // Shared with other responses for the same file
+}
+
+
+// A buffered body, either built for this response or shared with a cache
+#[derive(Debug)]
+enum Buffer {
+    Owned(Vec<u8>),
+    Shared(Rc<[u8]>)
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Buffer::Owned(bytes) => bytes,
+            Buffer::Shared(bytes) => bytes
+        }
+    }
+}
+
+
 #[derive(Debug)]
 pub struct ResponseBuilder {
     version: &'static str,
     status: Status,
-    headers: HashMap<String, String>,
-    body: Option<Vec<u8>>
+    headers: Headers,
+    body: Option<Buffer>
 }
 
 impl Default for ResponseBuilder {
@@ -39,28 +93,30 @@ impl Default for ResponseBuilder {
         ResponseBuilder {
             version: "HTTP/1.1",
             status: Status::Ok,
-            headers: HashMap::new(),
+            headers: Headers::default(),
             body: None
         }
     }
 }
 
-impl Into<Response> for ResponseBuilder {
-    fn into(mut self) -> Response {
-        if self.headers.get("Content-Length").is_none() {
-            let body_len = match self.body {
+impl From<ResponseBuilder> for Response {
+    fn from(mut builder: ResponseBuilder) -> Response {
+        if builder.headers.get("Content-Length").is_none() {
+            let body_len = match builder.body {
                 Some(ref b) => b.len(),
                 None => 0
             };
 
-            self.headers.insert("Content-Length".into(), body_len.to_string());
+            builder.headers.set("Content-Length", body_len.to_string());
         }
 
         Response {
-            version: self.version,
-            status: self.status,
-            headers: self.headers,
-            body: self.body
+            version: builder.version,
+            status: builder.status,
+            headers: builder.headers,
+            body: builder.body,
+            file: None,
+            user: None
         }
     }
 }
@@ -82,7 +138,7 @@ impl ResponseBuilder {
     }
 
     pub fn header<K: AsRef<str>, V: Into<String>>(mut self, key: K, value: V) -> Self {
-        self.headers.insert(title_case(key.as_ref()), value.into());
+        self.headers.set(key.as_ref(), value.into());
         self
     }
 
@@ -94,13 +150,55 @@ impl ResponseBuilder {
     }
 
     pub fn body(mut self, body: impl Into<Vec<u8>>) -> Response {
-        self.body = Some(body.into());
+        self.body = Some(Buffer::Owned(body.into()));
         self.into()
     }
 
+    // Finish with a body that's shared rather than copied, e.g. from the file cache
+    pub fn shared_body(mut self, body: Rc<[u8]>) -> Response {
+        self.body = Some(Buffer::Shared(body));
+        self.into()
+    }
+
+    // Finish with the first 'len' bytes of a file as the body
+    pub fn file(self, body: FileBody) -> Response {
+        let mut res: Response = self.header("Content-Length", body.len.to_string()).into();
+        res.file = Some(body);
+        res
+    }
+
     pub fn into_response(self) -> Response {
         self.into()
     }
+
+    // Finish with the built-in error page for this builder's status
+    pub fn error_page(self) -> Response {
+        let page = default_error_page(self.status);
+
+        self.header("Content-Type", "text/html; charset=utf-8")
+            .body(page)
+    }
+}
+
+
+fn default_error_page(status: Status) -> String {
+    let status: &str = status.into();
+
+    format!(r#"<!DOCTYPE html>
+<html lang="en">
+	<head>
+		<meta charset="UTF-8">
+		<title>{status}</title>
+		<style>
+			body {{ margin: 0; min-height: 100vh; display: flex; align-items: center; justify-content: center; background: #141414; color: #eee; font-family: system-ui, sans-serif; }}
+			h1 {{ font-weight: 400; }}
+		</style>
+	</head>
+	<body>
+		<h1>{status}</h1>
+	</body>
+</html>
+"#)
 }
 
 
@@ -108,33 +206,94 @@ impl ResponseBuilder {
 pub struct Response {
     version: &'static str,
     status: Status,
-    headers: HashMap<String, String>,
-    body: Option<Vec<u8>>
+    headers: Headers,
+    body: Option<Buffer>,
+    file: Option<FileBody>, // Sent after 'body'
+    user: Option<String> // Authenticated user, for logging
 }
 
 impl Response {
-    pub fn to_bytes(self) -> io::Result<Vec<u8>> {
+    // The built-in error page for a status
+    pub fn error(status: Status) -> Response {
+        ResponseBuilder::new()
+            .status(status)
+            .error_page()
+    }
+
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    pub fn set_header<K: AsRef<str>, V: Into<String>>(&mut self, key: K, value: V) {
+        self.headers.set(key.as_ref(), value.into());
+    }
+
+    // Add a header, keeping any existing values
+    pub fn add_header<K: AsRef<str>, V: Into<String>>(&mut self, key: K, value: V) {
+        self.headers.add(key.as_ref(), value.into());
+    }
+
+    pub fn remove_header<K: AsRef<str>>(&mut self, key: K) {
+        self.headers.remove(key.as_ref());
+    }
+
+    // Replace the body, keeping the status & other headers
+    pub fn set_body(&mut self, body: impl Into<Vec<u8>>) {
+        let body = body.into();
+
+        self.headers.set("Content-Length", body.len().to_string());
+        self.body = Some(Buffer::Owned(body));
+        self.file = None;
+    }
+
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    pub fn set_user(&mut self, user: Option<String>) {
+        self.user = user;
+    }
+
+    pub fn body_len(&self) -> u64 {
+        self.body.as_ref().map_or(0, |b| b.len() as u64) + self.file.as_ref().map_or(0, |f| f.len)
+    }
+
+    // The whole response, with any file body read into memory
+    pub fn into_bytes(self) -> io::Result<Vec<u8>> {
+        let (mut bytes, file) = self.into_parts()?;
+
+        if let Some(body) = file {
+            (&*body.file).take(body.len).read_to_end(&mut bytes)?;
+        }
+
+        Ok(bytes)
+    }
+
+    // The head & buffered body, plus a file body to send after them
+    pub fn into_parts(self) -> io::Result<(Vec<u8>, Option<FileBody>)> {
         let mut bytes = vec![];
         let status: &str = self.status.into();
 
-        bytes.write(self.version.as_bytes())?;
-        bytes.write(b" ")?;
-        bytes.write(status.as_bytes())?;
-        bytes.write(b"\r\n")?;
+        bytes.write_all(self.version.as_bytes())?;
+        bytes.write_all(b" ")?;
+        bytes.write_all(status.as_bytes())?;
+        bytes.write_all(b"\r\n")?;
 
-        for header in self.headers {
-            bytes.write(header.0.as_bytes())?;
-            bytes.write(b": ")?;
-            bytes.write(header.1.as_bytes())?;
-            bytes.write(b"\r\n")?;
+        for header in self.headers.0 {
+            bytes.write_all(header.0.as_bytes())?;
+            bytes.write_all(b": ")?;
+            bytes.write_all(header.1.as_bytes())?;
+            bytes.write_all(b"\r\n")?;
         }
 
-        bytes.write(b"\r\n")?;
+        bytes.write_all(b"\r\n")?;
 
-        if let Some(mut body) = self.body {
-            bytes.append(&mut body);
+        match self.body {
+            Some(Buffer::Owned(mut body)) => bytes.append(&mut body),
+            Some(Buffer::Shared(body)) => bytes.extend_from_slice(&body),
+            None => {}
         }
 
-        Ok(bytes)
+        Ok((bytes, self.file))
     }
 }
@@ -0,0 +1,229 @@
+// TCP & Unix domain sockets
+
+use std::{fmt, fs, io::{self, Read, Write}, net::{SocketAddr, TcpListener, TcpStream}, os::unix::{fs::{FileTypeExt, PermissionsExt}, io::{AsRawFd, FromRawFd, RawFd}, net::{UnixListener, UnixStream}}, path::PathBuf};
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Address {
+    Tcp(SocketAddr),
+    Unix { path: PathBuf, mode: Option<u32> }
+}
+
+impl Address {
+    // Compare the socket addresses alone, ignoring any options
+    pub fn same_socket(&self, other: &Address) -> bool {
+        match (self, other) {
+            (Address::Tcp(a), Address::Tcp(b)) => a == b,
+            (Address::Unix { path: a, .. }, Address::Unix { path: b, .. }) => a == b,
+            _ => false
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Tcp(addr) => write!(f, "{addr}"),
+            Address::Unix { path, .. } => write!(f, "unix:{}", path.display())
+        }
+    }
+}
+
+
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, Option<PathBuf>) // Socket file to remove once done, if we created it
+}
+
+impl Listener {
+    pub fn bind(addr: &Address) -> io::Result<Self> {
+        match addr {
+            Address::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr)?)),
+            Address::Unix { path, mode } => {
+                remove_stale_socket(path)?;
+                let listener = UnixListener::bind(path)?;
+
+                if let Some(mode) = mode {
+                    fs::set_permissions(path, fs::Permissions::from_mode(*mode))?;
+                }
+
+                Ok(Listener::Unix(listener, Some(path.clone())))
+            }
+        }
+    }
+
+    // Adopt an already listening socket, e.g. one inherited from systemd
+    pub fn from_fd(fd: RawFd) -> io::Result<Self> {
+        let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+
+        if unsafe { libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        match addr.ss_family as libc::c_int {
+            libc::AF_INET | libc::AF_INET6 => Ok(Listener::Tcp(unsafe { TcpListener::from_raw_fd(fd) })),
+            libc::AF_UNIX => Ok(Listener::Unix(unsafe { UnixListener::from_raw_fd(fd) }, None)),
+            family => Err(io::Error::new(io::ErrorKind::Unsupported, format!("Unsupported socket family {family} for fd {fd}")))
+        }
+    }
+
+    pub fn address(&self) -> io::Result<Address> {
+        match self {
+            Listener::Tcp(listener) => Ok(Address::Tcp(listener.local_addr()?)),
+            Listener::Unix(listener, _) => {
+                let addr = listener.local_addr()?;
+                let path = addr.as_pathname().unwrap_or(std::path::Path::new("")).to_owned();
+                Ok(Address::Unix { path, mode: None })
+            }
+        }
+    }
+
+    pub fn accept(&self) -> io::Result<(Stream, Peer)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept()?;
+                stream.set_nonblocking(true)?;
+                Ok((Stream::Tcp(stream), Peer::Tcp(addr)))
+            },
+            Listener::Unix(listener, _) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nonblocking(true)?;
+                let peer = peer_credentials(stream.as_raw_fd());
+                Ok((Stream::Unix(stream), peer))
+            }
+        }
+    }
+}
+
+#[cfg(feature = "io-uring")]
+impl Listener {
+    // Take ownership of a socket accepted from this listener elsewhere, e.g. by io_uring
+    pub fn adopt(&self, fd: RawFd) -> io::Result<(Stream, Peer)> {
+        match self {
+            Listener::Tcp(_) => {
+                let stream = unsafe { TcpStream::from_raw_fd(fd) };
+                let peer = stream.peer_addr()?;
+                Ok((Stream::Tcp(stream), Peer::Tcp(peer)))
+            },
+            Listener::Unix(..) => Ok((Stream::Unix(unsafe { UnixStream::from_raw_fd(fd) }), peer_credentials(fd)))
+        }
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener, _) => listener.as_raw_fd()
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, Some(path)) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+
+// Remove a socket file left behind by a previous server, unless something is still listening on it
+fn remove_stale_socket(path: &PathBuf) -> io::Result<()> {
+    let meta = match fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e)
+    };
+
+    if !meta.file_type().is_socket() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} exists and is not a socket", path.display())));
+    }
+
+    match UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is in use", path.display()))),
+        Err(_) => fs::remove_file(path)
+    }
+}
+
+
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream)
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Unix(stream) => stream.read(buf)
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Unix(stream) => stream.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Unix(stream) => stream.flush()
+        }
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Stream::Tcp(stream) => stream.as_raw_fd(),
+            Stream::Unix(stream) => stream.as_raw_fd()
+        }
+    }
+}
+
+
+// The other end of a connection
+#[derive(Debug, Clone, Copy)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    Unix { pid: Option<u32>, uid: Option<u32> }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{addr}"),
+            Peer::Unix { pid: Some(pid), uid: Some(uid) } => write!(f, "unix:pid={pid},uid={uid}"),
+            Peer::Unix { .. } => write!(f, "unix")
+        }
+    }
+}
+
+
+#[cfg(target_os = "linux")]
+fn peer_credentials(fd: RawFd) -> Peer {
+    let mut cred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+    let res = unsafe {
+        libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_PEERCRED, &mut cred as *mut _ as *mut libc::c_void, &mut len)
+    };
+
+    match res {
+        0 => Peer::Unix { pid: Some(cred.pid as u32), uid: Some(cred.uid) },
+        _ => Peer::Unix { pid: None, uid: None }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn peer_credentials(_fd: RawFd) -> Peer {
+    Peer::Unix { pid: None, uid: None }
+}
@@ -0,0 +1,39 @@
+// Helpers for tests that need requests
+
+use httparse::{Request, EMPTY_HEADER};
+
+
+// Parse a request, leaking its buffers so it can be returned
+pub fn request(method: &str, target: &str, headers: &[(&str, &str)]) -> Request<'static, 'static> {
+    let mut raw = format!("{method} {target} HTTP/1.1\r\nHost: localhost\r\n");
+
+    for (name, value) in headers {
+        raw += &format!("{name}: {value}\r\n");
+    }
+    raw += "\r\n";
+
+    let headers = Box::leak(vec![EMPTY_HEADER; headers.len() + 1].into_boxed_slice());
+    let mut req = Request::new(headers);
+    req.parse(Box::leak(raw.into_boxed_str()).as_bytes()).unwrap();
+
+    req
+}
+
+pub fn get(target: &str) -> Request<'static, 'static> {
+    request("GET", target, &[])
+}
+
+
+// Ways of writing an absolute path that rules for it must still match:
+// encoded, through '..', with doubled slashes & with '.' segments
+pub fn path_variants(path: &str) -> Vec<String> {
+    let first = path.as_bytes()[1];
+
+    vec![
+        path.to_string(),
+        format!("/%{first:02x}{}", &path[2..]),
+        format!("/pub/..{path}"),
+        format!("/{path}"),
+        format!("/.{path}"),
+    ]
+}
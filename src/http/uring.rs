@@ -0,0 +1,305 @@
+// io_uring backend, handing accepts, receives, sends & file reads to the kernel instead of polling sockets
+
+use super::{next_wakeup, notify_watchdog, respond, response::{FileBody, Response}, shutdown_requested, Connection, Handler, Listener, Status, Stream};
+use crate::{log, systemd};
+use io_uring::{opcode, squeue, types, IoUring};
+use std::{collections::HashMap, io, os::unix::io::AsRawFd, ptr, rc::Rc, time::{Duration, Instant}};
+
+
+const RING_SIZE: u32 = 256;
+const RECV_SIZE: usize = 2048;
+
+// Most bytes sent or read from a file at once
+const CHUNK_SIZE: usize = 1 << 20;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const SEND_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Longest wait for completions, so signals are noticed even if they don't interrupt it
+const MAX_WAIT: Duration = Duration::from_millis(250);
+
+// Operations, kept in the low bits of each entry's user data with a client or listener index above them
+const ACCEPT: u64 = 0;
+const RECV: u64 = 1;
+const SEND: u64 = 2;
+const READ: u64 = 3;
+
+fn user_data(key: usize, op: u64) -> u64 {
+    (key as u64) << 2 | op
+}
+
+
+// A client always has exactly one operation in flight, so its buffers stay put until it completes
+struct Client {
+    stream: Stream,
+    conn: Connection,
+    recv: Box<[u8]>,
+    out: Vec<u8>, // The response head & body, then chunks read from a file
+    written: usize,
+    file: Option<(FileBody, u64)>, // Sent after 'out', & how much of it was
+    closing: bool, // Shut down, waiting for its operation to finish
+    timeout: Duration
+}
+
+
+struct Uring {
+    ring: IoUring,
+    clients: HashMap<usize, Client>,
+    next_key: usize
+}
+
+impl Uring {
+    fn push(&mut self, entry: squeue::Entry) -> io::Result<()> {
+        // Safety: every buffer an entry points to is owned by its client, which isn't dropped until the entry completes
+        while unsafe { self.ring.submission().push(&entry) }.is_err() {
+            self.ring.submit()?;
+        }
+
+        Ok(())
+    }
+
+    fn accept(&mut self, listeners: &[Listener], index: usize) -> io::Result<()> {
+        let entry = opcode::Accept::new(types::Fd(listeners[index].as_raw_fd()), ptr::null_mut(), ptr::null_mut())
+            .flags(libc::SOCK_CLOEXEC)
+            .build()
+            .user_data(user_data(index, ACCEPT));
+
+        self.push(entry)
+    }
+
+    fn add(&mut self, stream: Stream, conn: Connection) -> usize {
+        let key = self.next_key;
+        self.next_key = self.next_key.wrapping_add(1);
+
+        self.clients.insert(key, Client {
+            stream,
+            conn,
+            recv: vec![0; RECV_SIZE].into_boxed_slice(),
+            out: vec![],
+            written: 0,
+            file: None,
+            closing: false,
+            timeout: TIMEOUT
+        });
+
+        key
+    }
+
+    fn close(&mut self, key: usize) {
+        if self.clients.remove(&key).is_some() {
+            log::debug!(Client, "Client Disconnected"; "client" => key);
+        }
+    }
+
+    // Queue a client's next operation: sending the rest of its response, reading more of a file, or receiving another request
+    fn next(&mut self, key: usize) -> io::Result<()> {
+        let Some(client) = self.clients.get_mut(&key) else { return Ok(()) };
+        let fd = types::Fd(client.stream.as_raw_fd());
+
+        let entry = match client.file {
+            _ if client.written < client.out.len() => {
+                let rest = &client.out[client.written..];
+                opcode::Send::new(fd, rest.as_ptr(), rest.len() as u32).build().user_data(user_data(key, SEND))
+            },
+            Some((ref mut body, offset)) if offset < body.len => {
+                let len = ((body.len - offset) as usize).min(CHUNK_SIZE);
+
+                // Like the polling writer, stop using a mapping once its file is truncated
+                if body.map.is_some() && body.file.metadata()?.len() < body.len {
+                    log::debug!(Client, "Mapped File Truncated, Copying");
+                    body.map = None;
+                }
+
+                match body.map {
+                    Some(ref map) => opcode::Send::new(fd, map[offset as usize..].as_ptr(), len as u32)
+                        .build()
+                        .user_data(user_data(key, SEND)),
+                    None => {
+                        client.out.resize(len, 0);
+
+                        opcode::Read::new(types::Fd(body.file.as_raw_fd()), client.out.as_mut_ptr(), len as u32)
+                            .offset(offset)
+                            .build()
+                            .user_data(user_data(key, READ))
+                    }
+                }
+            },
+            _ => {
+                client.out.clear();
+                client.written = 0;
+                client.file = None;
+                client.timeout = TIMEOUT;
+
+                opcode::Recv::new(fd, client.recv.as_mut_ptr(), RECV_SIZE as u32).build().user_data(user_data(key, RECV))
+            }
+        };
+
+        self.push(entry)
+    }
+
+    fn complete<R: Into<Response>, T>(&mut self, listeners: &[Listener], app: &Handler<T, R>, state: &Rc<T>, data: u64, res: i32) -> io::Result<()> {
+        let (key, op) = ((data >> 2) as usize, data & 3);
+
+        if op == ACCEPT {
+            match res {
+                fd if fd >= 0 => match listeners[key].adopt(fd) {
+                    Ok((stream, peer)) => {
+                        let client = self.add(stream, Connection { listener: key, peer });
+                        log::debug!(Client, "Client Connected"; "client" => client, "peer" => peer, "listener" => key);
+
+                        if let Err(e) = self.next(client) {
+                            log::error!(Client, "Error Adding Client: {e}"; "peer" => peer);
+                            self.close(client);
+                        }
+                    },
+                    Err(e) => log::error!(Server, "Error Accepting Client: {e}"; "listener" => key)
+                },
+                err => log::error!(Server, "Error Accepting Client: {}", io::Error::from_raw_os_error(-err); "listener" => key)
+            }
+
+            return self.accept(listeners, key);
+        }
+
+        let Some(client) = self.clients.get_mut(&key) else { return Ok(()) };
+
+        if client.closing || res <= 0 {
+            match res {
+                0 if op == READ => log::debug!(Client, "Error Sending Response: File truncated while sending"; "client" => key),
+                err if err < 0 && !client.closing => log::debug!(Client, "Connection Error: {}", io::Error::from_raw_os_error(-err); "client" => key),
+                _ => {}
+            }
+
+            self.close(key);
+            return Ok(());
+        }
+
+        let len = res as usize;
+
+        match op {
+            RECV => if let Some(res) = respond(app, state, &client.conn, key, &client.recv[..len]) {
+                let (head, file) = res.into_parts()
+                    .or_else(|_| Response::error(Status::InternalServerError).into_parts())?;
+
+                client.out = head;
+                client.file = file.map(|body| (body, 0));
+                client.timeout = SEND_TIMEOUT;
+            },
+            SEND if client.written < client.out.len() => client.written += len,
+            SEND => if let Some((_, ref mut offset)) = client.file {
+                *offset += len as u64;
+            },
+            _ => {
+                client.out.truncate(len);
+                client.written = 0;
+
+                if let Some((_, ref mut offset)) = client.file {
+                    *offset += len as u64;
+                }
+            }
+        }
+
+        if op != RECV {
+            client.timeout = SEND_TIMEOUT;
+        }
+
+        if let Err(e) = self.next(key) {
+            log::debug!(Client, "Error Sending Response: {e}"; "client" => key);
+            self.close(key);
+        }
+
+        Ok(())
+    }
+
+    // Shut down clients that timed out, their pending operation then completes & closes them
+    fn sub_time(&mut self, time: Duration) {
+        for client in self.clients.values_mut() {
+            client.timeout = client.timeout.saturating_sub(time);
+
+            if client.timeout.is_zero() && !client.closing {
+                unsafe { libc::shutdown(client.stream.as_raw_fd(), libc::SHUT_RDWR) };
+                client.closing = true;
+            }
+        }
+    }
+
+    fn next_timeout(&self) -> Option<Duration> {
+        self.clients.values().filter(|client| !client.closing).map(|client| client.timeout).min()
+    }
+
+    // Wait for completions, returning them as (user data, result) pairs
+    fn wait(&mut self, timeout: Duration) -> io::Result<Vec<(u64, i32)>> {
+        let ts = types::Timespec::from(timeout);
+        let args = types::SubmitArgs::new().timespec(&ts);
+
+        match self.ring.submitter().submit_with_args(1, &args) {
+            Ok(_) => {},
+            Err(e) if e.raw_os_error() == Some(libc::ETIME) || e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e)
+        }
+
+        Ok(self.ring.completion().map(|cqe| (cqe.user_data(), cqe.result())).collect())
+    }
+
+    // Shut down every client & wait for their operations to finish, so none still point at freed buffers
+    fn drain(&mut self) -> io::Result<()> {
+        for client in self.clients.values_mut() {
+            unsafe { libc::shutdown(client.stream.as_raw_fd(), libc::SHUT_RDWR) };
+        }
+
+        let start = Instant::now();
+
+        while !self.clients.is_empty() && start.elapsed() < MAX_WAIT {
+            for (data, _) in self.wait(MAX_WAIT)? {
+                if data & 3 != ACCEPT {
+                    self.clients.remove(&((data >> 2) as usize));
+                }
+            }
+        }
+
+        // Leak anything the kernel may still write to
+        std::mem::forget(std::mem::take(&mut self.clients));
+        Ok(())
+    }
+}
+
+
+// Serve requests until SIGINT or SIGTERM is received
+pub fn serve<R: Into<Response>, T>(listeners: &[Listener], app: Handler<T, R>, state: Rc<T>) -> io::Result<()> {
+    let ring = IoUring::new(RING_SIZE)
+        .map_err(|e| io::Error::new(e.kind(), format!("Can't set up io_uring: {e}")))?;
+
+    if !ring.params().is_feature_ext_arg() {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "The io-uring backend needs Linux 5.11 or newer"));
+    }
+
+    let mut uring = Uring { ring, clients: HashMap::new(), next_key: 0 };
+
+    for i in 0..listeners.len() {
+        uring.accept(listeners, i)?;
+    }
+
+    let mut prev_time = Instant::now();
+    let mut watchdog = systemd::watchdog_interval().map(|interval| (interval, Instant::now()));
+
+    log::debug!(Server, "Using io_uring Backend");
+    systemd::notify("READY=1");
+
+    loop {
+        if shutdown_requested() {
+            return uring.drain();
+        }
+
+        notify_watchdog(&mut watchdog);
+
+        let timeout = next_wakeup(uring.next_timeout(), watchdog).map_or(MAX_WAIT, |t| t.min(MAX_WAIT));
+        let completions = uring.wait(timeout)?;
+
+        let now = Instant::now();
+        uring.sub_time(now.duration_since(prev_time));
+        prev_time = now;
+
+        for (data, res) in completions {
+            uring.complete(listeners, &app, &state, data, res)?;
+        }
+    }
+}
@@ -0,0 +1,138 @@
+// Request targets
+
+use super::Status;
+use crate::path;
+use percent_encoding::percent_decode_str;
+use std::borrow::Cow;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Url<'a> {
+    pub path: &'a str, // Still percent-encoded, "*" for an asterisk-form target
+    pub query: Option<&'a str>,
+    pub authority: Option<&'a str> // Host from an absolute-form target
+}
+
+impl<'a> Url<'a> {
+    // Parse an origin-form ("/path?query"), absolute-form ("http://host/path")
+    // or asterisk-form ("*") request target, dropping any fragment
+    pub fn parse(target: &'a str) -> Option<Self> {
+        let target = target.split('#').next().unwrap_or(target);
+
+        if target == "*" {
+            return Some(Url { path: "*", query: None, authority: None });
+        }
+
+        let (authority, rest) = match target.split_once("://") {
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case("http") || scheme.eq_ignore_ascii_case("https") => {
+                let end = rest.find(['/', '?']).unwrap_or(rest.len());
+                (Some(&rest[..end]), &rest[end..])
+            },
+            Some(_) => return None,
+            None => (None, target)
+        };
+
+        let (path, query) = match rest.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (rest, None)
+        };
+
+        match path {
+            "" if authority.is_some() => Some(Url { path: "/", query, authority }),
+            _ if path.starts_with('/') => Some(Url { path, query, authority }),
+            _ => None
+        }
+    }
+
+    pub fn is_asterisk(&self) -> bool {
+        self.path == "*"
+    }
+
+    // The percent-decoded path, or None if it isn't valid UTF-8
+    pub fn decoded_path(&self) -> Option<Cow<'a, str>> {
+        percent_decode_str(self.path).decode_utf8().ok()
+    }
+
+    // The decoded path with '.' & '..' resolved, which access rules are checked against.
+    // Fails with 400 if it isn't valid UTF-8, or 403 if it climbs out of the root
+    pub fn normalized_path(&self) -> Result<String, Status> {
+        let decoded = self.decoded_path().ok_or(Status::BadRequest)?;
+        path::normalize_url(&decoded).ok_or(Status::Forbidden)
+    }
+
+    // Decoded query parameters in order, '+' meaning a space
+    pub fn params(&self) -> Vec<(String, String)> {
+        let decode = |s: &str| percent_decode_str(&s.replace('+', " ")).decode_utf8_lossy().into_owned();
+
+        self.query.unwrap_or("")
+            .split('&')
+            .filter(|p| !p.is_empty())
+            .map(|p| match p.split_once('=') {
+                Some((key, value)) => (decode(key), decode(value)),
+                None => (decode(p), String::new())
+            })
+            .collect()
+    }
+
+    // The first value of a query parameter
+    pub fn param(&self, name: &str) -> Option<String> {
+        self.params().into_iter().find(|(key, _)| key == name).map(|(_, value)| value)
+    }
+
+    // Append the query to a location, if there is one
+    pub fn with_query(&self, location: &str) -> String {
+        match self.query.filter(|q| !q.is_empty()) {
+            Some(query) if location.contains('?') => format!("{location}&{query}"),
+            Some(query) => format!("{location}?{query}"),
+            None => location.to_string()
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let url = Url::parse("/a/b?x=1&y=2#frag").unwrap();
+        assert_eq!((url.path, url.query, url.authority), ("/a/b", Some("x=1&y=2"), None));
+
+        let url = Url::parse("http://Example.com:8080/a?q").unwrap();
+        assert_eq!((url.path, url.query, url.authority), ("/a", Some("q"), Some("Example.com:8080")));
+
+        let url = Url::parse("HTTPS://example.com").unwrap();
+        assert_eq!((url.path, url.authority), ("/", Some("example.com")));
+
+        let url = Url::parse("https://example.com?q").unwrap();
+        assert_eq!((url.path, url.query), ("/", Some("q")));
+
+        assert!(Url::parse("*").unwrap().is_asterisk());
+        assert_eq!(Url::parse("ftp://example.com/a"), None);
+        assert_eq!(Url::parse("a/b"), None);
+        assert_eq!(Url::parse(""), None);
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(Url::parse("/a%20b").unwrap().decoded_path().as_deref(), Some("/a b"));
+        assert_eq!(Url::parse("/%ff").unwrap().decoded_path(), None);
+
+        assert_eq!(Url::parse("/%73ecret/./x/").unwrap().normalized_path(), Ok("/secret/x/".into()));
+        assert_eq!(Url::parse("/a/..%2f..%2fb").unwrap().normalized_path(), Err(Status::Forbidden));
+        assert_eq!(Url::parse("/%ff").unwrap().normalized_path(), Err(Status::BadRequest));
+    }
+
+    #[test]
+    fn params() {
+        let url = Url::parse("/?a=1&b=x+y&a=2&c&d=%26").unwrap();
+
+        assert_eq!(url.param("a").as_deref(), Some("1"));
+        assert_eq!(url.param("b").as_deref(), Some("x y"));
+        assert_eq!(url.param("c").as_deref(), Some(""));
+        assert_eq!(url.param("d").as_deref(), Some("&"));
+        assert_eq!(url.with_query("/next"), "/next?a=1&b=x+y&a=2&c&d=%26");
+        assert_eq!(Url::parse("/").unwrap().with_query("/next?z"), "/next?z");
+    }
+}
@@ -0,0 +1,145 @@
+// Non-blocking response writer
+
+use super::{response::FileBody, Stream};
+use crate::log;
+use memmap2::Mmap;
+use std::{collections::VecDeque, fs, io::{self, Write}, os::unix::{fs::FileExt, io::{AsRawFd, RawFd}}, rc::Rc};
+
+
+// Most bytes sent or copied from a file at once
+const CHUNK_SIZE: usize = 1 << 20;
+
+
+#[derive(Debug)]
+struct FileSend {
+    file: Rc<fs::File>,
+    map: Option<Rc<Mmap>>, // Written from directly while the file is still long enough
+    offset: u64,
+    end: u64,
+    sendfile: bool // Cleared if the kernel can't send this file directly
+}
+
+
+// A response still being written
+#[derive(Debug)]
+struct Pending {
+    buf: Vec<u8>,
+    written: usize,
+    file: Option<FileSend> // Sent once 'buf' is written
+}
+
+
+#[derive(Debug, Default)]
+pub struct Writer {
+    queue: VecDeque<Pending>
+}
+
+impl Writer {
+    pub fn push(&mut self, buf: Vec<u8>, file: Option<FileBody>) {
+        let file = file.map(|body| FileSend { file: body.file, map: body.map, offset: 0, end: body.len, sendfile: true });
+        self.queue.push_back(Pending { buf, written: 0, file });
+    }
+
+    // Write as much as the socket takes without blocking, true once everything is sent
+    pub fn flush(&mut self, stream: &mut Stream) -> io::Result<bool> {
+        while let Some(pending) = self.queue.front_mut() {
+            while pending.written < pending.buf.len() {
+                match stream.write(&pending.buf[pending.written..]) {
+                    Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                    Ok(n) => pending.written += n,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e)
+                }
+            }
+
+            let file = match pending.file {
+                Some(ref mut file) if file.offset < file.end => file,
+                _ => {
+                    self.queue.pop_front();
+                    continue;
+                }
+            };
+            let len = ((file.end - file.offset) as usize).min(CHUNK_SIZE);
+
+            if let Some(ref map) = file.map {
+                // Pages past the end of a truncated file fault when touched, so copy through read() instead.
+                // Truncating between this check & the write can still fault, mapped files should be replaced, not rewritten
+                if file.file.metadata()?.len() < file.end {
+                    log::debug!(Client, "Mapped File Truncated, Copying");
+                    file.map = None;
+                    file.sendfile = false;
+                    continue;
+                }
+
+                let start = file.offset as usize;
+
+                match stream.write(&map[start..start + len]) {
+                    Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                    Ok(n) => file.offset += n as u64,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+                    Err(e) => return Err(e)
+                }
+                continue;
+            }
+
+            if file.sendfile {
+                match sendfile(stream.as_raw_fd(), &file.file, &mut file.offset, len) {
+                    Ok(0) => return Err(truncated()),
+                    Ok(_) => continue,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) if unsupported(&e) => {
+                        log::debug!(Client, "Can't Use Sendfile, Copying: {e}");
+                        file.sendfile = false;
+                    },
+                    Err(e) => return Err(e)
+                }
+            }
+
+            // Copy the next chunk through 'buf' instead
+            let mut chunk = vec![0; len];
+            let read = file.file.read_at(&mut chunk, file.offset)?;
+
+            if read == 0 {
+                return Err(truncated());
+            }
+
+            chunk.truncate(read);
+            file.offset += read as u64;
+            pending.buf = chunk;
+            pending.written = 0;
+        }
+
+        Ok(true)
+    }
+}
+
+
+// The file shrank after its Content-Length was sent, so the connection can't be reused
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "File truncated while sending")
+}
+
+fn unsupported(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::Unsupported || matches!(e.raw_os_error(), Some(libc::EINVAL | libc::ENOSYS | libc::EOPNOTSUPP))
+}
+
+#[cfg(target_os = "linux")]
+fn sendfile(out: RawFd, file: &fs::File, offset: &mut u64, len: usize) -> io::Result<usize> {
+    let mut off = *offset as libc::off_t;
+
+    match unsafe { libc::sendfile(out, file.as_raw_fd(), &mut off, len) } {
+        -1 => Err(io::Error::last_os_error()),
+        sent => {
+            *offset = off as u64;
+            Ok(sent as usize)
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sendfile(_out: RawFd, _file: &fs::File, _offset: &mut u64, _len: usize) -> io::Result<usize> {
+    Err(io::ErrorKind::Unsupported.into())
+}
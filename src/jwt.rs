@@ -0,0 +1,329 @@
+// Bearer token (JWT) validation
+
+use crate::{path::Prefixes, http::{self, response::{Response, ResponseBuilder}, Status}};
+use httparse::Request;
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde_json::Value;
+use std::{fmt, fs, path::Path};
+
+
+struct Key {
+    id: Option<String>, // "kid" from a JWKS file
+    alg: Option<Algorithm>, // Any algorithm of the key's family if unset
+    key: DecodingKey
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Key").field("id", &self.id).field("alg", &self.alg).finish_non_exhaustive()
+    }
+}
+
+
+// Claims a token needs for one path prefix
+#[derive(Debug)]
+struct Rule {
+    required: bool,
+    claims: Vec<(String, String)>
+}
+
+// Whether a claim holds 'expected', either as a value, an array element, or a space separated word (e.g. "scope")
+fn claim_matches(claim: Option<&Value>, expected: &str) -> bool {
+    match claim {
+        Some(Value::String(s)) => s == expected || s.split_whitespace().any(|w| w == expected),
+        Some(Value::Array(items)) => items.iter().any(|v| claim_matches(Some(v), expected)),
+        Some(Value::Bool(b)) => b.to_string() == expected,
+        Some(Value::Number(n)) => n.to_string() == expected,
+        _ => false
+    }
+}
+
+
+enum Outcome {
+    Allowed(Option<String>), // Token subject
+    Invalid(&'static str),
+    Forbidden
+}
+
+
+#[derive(Debug)]
+pub struct Jwt {
+    pub realm: String,
+    keys: Vec<Key>,
+    audience: Vec<String>,
+    issuer: Vec<String>,
+    pub leeway: u64,
+    rules: Prefixes<Rule>
+}
+
+impl Jwt {
+    pub fn new() -> Self {
+        Jwt {
+            realm: "Restricted".into(),
+            keys: vec![],
+            audience: vec![],
+            issuer: vec![],
+            leeway: 0,
+            rules: Prefixes::new()
+        }
+    }
+
+    // Add a key from "<ALG> <secret>" for HMAC, or "<ALG> <file.pem>" otherwise
+    pub fn add_key(&mut self, value: &str) -> Result<(), String> {
+        let (alg, key) = value.trim().split_once(char::is_whitespace)
+            .ok_or_else(|| format!("Expected '<algorithm> <key>', found '{value}'"))?;
+        let alg: Algorithm = alg.parse().map_err(|_| format!("Unknown JWT algorithm '{alg}'"))?;
+        let key = key.trim();
+
+        let pem = || fs::read(key).map_err(|e| format!("Can't read '{key}': {e}"));
+
+        let key = match alg {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => Ok(DecodingKey::from_secret(key.as_bytes())),
+            Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(&pem()?),
+            Algorithm::EdDSA => DecodingKey::from_ed_pem(&pem()?),
+            _ => DecodingKey::from_rsa_pem(&pem()?)
+        }.map_err(|e| format!("Invalid {alg:?} key: {e}"))?;
+
+        self.keys.push(Key { id: None, alg: Some(alg), key });
+        Ok(())
+    }
+
+    // Add every usable key from a JWKS file
+    pub fn load_jwks<P: AsRef<Path>>(&mut self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path).map_err(|e| format!("Can't read '{}': {e}", path.display()))?;
+        let set: JwkSet = serde_json::from_str(&source).map_err(|e| format!("Invalid JWKS '{}': {e}", path.display()))?;
+
+        for jwk in set.keys.iter().filter(|k| k.is_supported() || k.common.key_algorithm.is_none()) {
+            let key = DecodingKey::from_jwk(jwk).map_err(|e| format!("Invalid key in '{}': {e}", path.display()))?;
+            let alg = jwk.common.key_algorithm.and_then(|a| a.to_string().parse().ok());
+
+            self.keys.push(Key { id: jwk.common.key_id.clone(), alg, key });
+        }
+
+        Ok(())
+    }
+
+    pub fn set_audience(&mut self, value: &str) {
+        self.audience = value.split(',').map(|a| a.trim().to_string()).filter(|a| !a.is_empty()).collect();
+    }
+
+    pub fn set_issuer(&mut self, value: &str) {
+        self.issuer = value.split(',').map(|i| i.trim().to_string()).filter(|i| !i.is_empty()).collect();
+    }
+
+    // Set an option for a path prefix, "require" or "claim"
+    pub fn set_rule(&mut self, prefix: &str, option: &str, value: &str) -> Result<(), String> {
+        let rule = self.rules.entry(prefix, || Rule { required: true, claims: vec![] });
+
+        match option {
+            "require" => rule.required = value == "true",
+            "claim" => match value.split_once('=') {
+                Some((claim, expected)) => rule.claims.push((claim.trim().into(), expected.trim().into())),
+                None => return Err(format!("Expected '<claim>=<value>', found '{value}'"))
+            },
+            _ => return Err(format!("Unknown JWT option '{option}'"))
+        }
+
+        Ok(())
+    }
+
+    pub fn has_keys(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    fn validation(&self, alg: Algorithm) -> Validation {
+        let mut validation = Validation::new(alg);
+        validation.leeway = self.leeway;
+        validation.validate_nbf = true;
+
+        match self.audience.is_empty() {
+            true => validation.validate_aud = false,
+            false => validation.set_audience(&self.audience)
+        }
+        if !self.issuer.is_empty() {
+            validation.set_issuer(&self.issuer);
+        }
+
+        validation
+    }
+
+    fn verify(&self, rule: &Rule, token: &str) -> Outcome {
+        let header = match jsonwebtoken::decode_header(token) {
+            Ok(h) => h,
+            Err(_) => return Outcome::Invalid("Malformed token")
+        };
+
+        // Only try keys that could have signed this token
+        let keys = self.keys.iter().filter(|k| {
+            k.alg.map_or(k.key.family() == header.alg.family(), |a| a == header.alg)
+                && (header.kid.is_none() || k.id.is_none() || k.id == header.kid)
+        });
+
+        let mut error = "No matching key";
+
+        for key in keys {
+            let claims = match jsonwebtoken::decode::<Value>(token, &key.key, &self.validation(header.alg)) {
+                Ok(data) => data.claims,
+                Err(e) => {
+                    use jsonwebtoken::errors::ErrorKind;
+
+                    error = match e.kind() {
+                        ErrorKind::ExpiredSignature => "Token has expired",
+                        ErrorKind::ImmatureSignature => "Token is not valid yet",
+                        ErrorKind::InvalidAudience => "Invalid audience",
+                        ErrorKind::InvalidIssuer => "Invalid issuer",
+                        ErrorKind::MissingRequiredClaim(_) => "Missing required claim",
+                        _ => "Invalid signature"
+                    };
+                    continue;
+                }
+            };
+
+            if !rule.claims.iter().all(|(claim, expected)| claim_matches(claims.get(claim), expected)) {
+                return Outcome::Forbidden;
+            }
+
+            return Outcome::Allowed(claims.get("sub").and_then(Value::as_str).map(String::from));
+        }
+
+        Outcome::Invalid(error)
+    }
+
+    fn challenge(&self, status: Status, error: Option<(&str, &str)>) -> Response {
+        let mut value = format!("Bearer realm=\"{}\"", self.realm);
+
+        if let Some((error, description)) = error {
+            value += &format!(", error=\"{error}\", error_description=\"{description}\"");
+        }
+
+        ResponseBuilder::new()
+            .status(status)
+            .header("WWW-Authenticate", value)
+            .error_page()
+    }
+
+    // Check a request's bearer token, giving the token's subject if any,
+    // or the response to send instead
+    pub fn check(&self, path: &str, req: &Request) -> Result<Option<String>, Response> {
+        // A longer prefix can stop requiring a token
+        let rule = match self.rules.get(path).filter(|rule| rule.required) {
+            Some(rule) => rule,
+            None => return Ok(None)
+        };
+
+        let header = http::header(req, "Authorization").unwrap_or("").trim();
+        let token = match header.split_once(' ') {
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("Bearer") => token.trim(),
+            _ => return Err(self.challenge(Status::Unauthorized, None))
+        };
+
+        match self.verify(rule, token) {
+            Outcome::Allowed(subject) => Ok(subject),
+            Outcome::Invalid(reason) => Err(self.challenge(Status::Unauthorized, Some(("invalid_token", reason)))),
+            Outcome::Forbidden => Err(self.challenge(Status::Forbidden, Some(("insufficient_scope", "Missing required claims"))))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{testing::{self, path_variants}, Url};
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn token(claims: Value) -> String {
+        jsonwebtoken::encode(&Header::default(), &claims, &EncodingKey::from_secret(b"secret")).unwrap()
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    // The subject or the error status
+    fn check(jwt: &Jwt, target: &str, token: Option<&str>) -> Result<Option<String>, Status> {
+        let authorization = token.map(|t| format!("Bearer {t}"));
+        let headers: Vec<_> = authorization.as_deref().map(|a| ("Authorization", a)).into_iter().collect();
+        let req = testing::request("GET", target, &headers);
+        let path = Url::parse(target).unwrap().normalized_path().unwrap();
+
+        jwt.check(&path, &req).map_err(|res| res.status())
+    }
+
+    fn jwt() -> Jwt {
+        let mut jwt = Jwt::new();
+        jwt.add_key("HS256 secret").unwrap();
+        jwt.set_rule("/api", "require", "true").unwrap();
+        jwt
+    }
+
+    #[test]
+    fn rule_bypasses() {
+        let jwt = jwt();
+
+        for target in path_variants("/api/users") {
+            assert_eq!(check(&jwt, &target, None), Err(Status::Unauthorized), "{target} wasn't protected");
+        }
+
+        assert_eq!(check(&jwt, "/apis/users", None), Ok(None));
+    }
+
+    #[test]
+    fn tokens() {
+        let jwt = jwt();
+        let valid = token(json!({ "sub": "ann", "exp": now() + 60 }));
+
+        assert_eq!(check(&jwt, "/api/x", Some(&valid)), Ok(Some("ann".into())));
+        assert_eq!(check(&jwt, "/api/x", Some(&token(json!({ "exp": now() + 60 })))), Ok(None));
+
+        assert_eq!(check(&jwt, "/api/x", Some(&token(json!({ "sub": "ann", "exp": now() - 120 })))), Err(Status::Unauthorized));
+        assert_eq!(check(&jwt, "/api/x", Some("not.a.token")), Err(Status::Unauthorized));
+
+        let forged = jsonwebtoken::encode(&Header::default(), &json!({ "exp": now() + 60 }), &EncodingKey::from_secret(b"other")).unwrap();
+        assert_eq!(check(&jwt, "/api/x", Some(&forged)), Err(Status::Unauthorized));
+    }
+
+    #[test]
+    fn leeway() {
+        let mut jwt = jwt();
+        let expired = token(json!({ "exp": now() - 30 }));
+        assert_eq!(check(&jwt, "/api/x", Some(&expired)), Err(Status::Unauthorized));
+
+        jwt.leeway = 60;
+        assert_eq!(check(&jwt, "/api/x", Some(&expired)), Ok(None));
+    }
+
+    #[test]
+    fn audience_and_issuer() {
+        let mut jwt = jwt();
+        jwt.set_audience("ws, other");
+        jwt.set_issuer("https://auth.example.com");
+
+        let claims = |aud: &str, iss: &str| token(json!({ "aud": aud, "iss": iss, "exp": now() + 60 }));
+
+        assert_eq!(check(&jwt, "/api/x", Some(&claims("ws", "https://auth.example.com"))), Ok(None));
+        assert_eq!(check(&jwt, "/api/x", Some(&claims("other", "https://auth.example.com"))), Ok(None));
+        assert_eq!(check(&jwt, "/api/x", Some(&claims("else", "https://auth.example.com"))), Err(Status::Unauthorized));
+        assert_eq!(check(&jwt, "/api/x", Some(&claims("ws", "https://evil.example.com"))), Err(Status::Unauthorized));
+    }
+
+    #[test]
+    fn claims() {
+        let mut jwt = jwt();
+        jwt.set_rule("/api/admin", "claim", "scope=admin").unwrap();
+        jwt.set_rule("/api/public", "require", "false").unwrap();
+
+        let scoped = |scope: Value| token(json!({ "scope": scope, "exp": now() + 60 }));
+
+        assert_eq!(check(&jwt, "/api/admin/x", Some(&scoped(json!("read admin")))), Ok(None));
+        assert_eq!(check(&jwt, "/api/admin/x", Some(&scoped(json!(["admin"])))), Ok(None));
+        assert_eq!(check(&jwt, "/api/admin/x", Some(&scoped(json!("read")))), Err(Status::Forbidden));
+        assert_eq!(check(&jwt, "/api/x", Some(&scoped(json!("read")))), Ok(None));
+
+        // A longer prefix can stop requiring tokens
+        assert_eq!(check(&jwt, "/api/public/x", None), Ok(None));
+    }
+}
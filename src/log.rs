@@ -0,0 +1,435 @@
+// Leveled & structured logging
+
+use crate::signal;
+use std::{collections::HashMap, fmt, fs::{self, File, OpenOptions}, io::{self, Write}, path::PathBuf, str::FromStr, sync::Mutex, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace
+}
+
+impl Level {
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Off => "off",
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace"
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "off" | "none" => Ok(Level::Off),
+            "error" => Ok(Level::Error),
+            "warn" | "warning" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            _ => Err(format!("Unknown log level '{s}'"))
+        }
+    }
+}
+
+
+// Event categories, each with its own configurable level
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Category {
+    Server, // Listening, polling & startup
+    Client, // Connections being added & removed
+    Serve,  // Reading files from the served directory
+    Access  // One event per handled request
+}
+
+impl Category {
+    pub fn name(self) -> &'static str {
+        match self {
+            Category::Server => "server",
+            Category::Client => "client",
+            Category::Serve => "serve",
+            Category::Access => "access"
+        }
+    }
+}
+
+impl FromStr for Category {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "server" => Ok(Category::Server),
+            "client" => Ok(Category::Client),
+            "serve" => Ok(Category::Serve),
+            "access" => Ok(Category::Access),
+            _ => Err(format!("Unknown log category '{s}'"))
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json
+}
+
+
+#[derive(Debug)]
+pub struct LogConfig {
+    pub level: Level,
+    pub categories: HashMap<Category, Level>,
+    pub format: Format,
+    pub file: Option<PathBuf>, // Log to stderr if None
+    pub max_size: Option<u64>,
+    pub max_age: Option<Duration>,
+    pub keep: usize // Number of rotated files to keep
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: Level::Info,
+            categories: HashMap::new(),
+            format: Format::Text,
+            file: None,
+            max_size: None,
+            max_age: None,
+            keep: 5
+        }
+    }
+}
+
+impl LogConfig {
+    fn level(&self, category: Category) -> Level {
+        *self.categories.get(&category).unwrap_or(&self.level)
+    }
+}
+
+
+enum Output {
+    Stderr,
+    File { file: File, size: u64, opened: SystemTime }
+}
+
+
+struct Logger {
+    config: LogConfig,
+    output: Output
+}
+
+impl Logger {
+    fn open(config: LogConfig) -> io::Result<Self> {
+        let output = match config.file {
+            Some(ref path) => Self::open_file(path)?,
+            None => Output::Stderr
+        };
+
+        Ok(Logger { config, output })
+    }
+
+    fn open_file(path: &PathBuf) -> io::Result<Output> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let meta = file.metadata()?;
+
+        Ok(Output::File {
+            size: meta.len(),
+            opened: SystemTime::now(),
+            file
+        })
+    }
+
+    // Re-open the log file, e.g. after an external tool moved it
+    fn reopen(&mut self) -> io::Result<()> {
+        if let Some(ref path) = self.config.file {
+            self.output = Self::open_file(path)?;
+        }
+
+        Ok(())
+    }
+
+    // Shift 'file.N' to 'file.N+1', discarding the oldest, then start a new file
+    fn rotate(&mut self) -> io::Result<()> {
+        let path = match self.config.file {
+            Some(ref p) => p.clone(),
+            None => return Ok(())
+        };
+        let numbered = |n: usize| {
+            let mut name = path.clone().into_os_string();
+            name.push(format!(".{n}"));
+            PathBuf::from(name)
+        };
+
+        if self.config.keep == 0 {
+            fs::remove_file(&path)?;
+        }
+        else {
+            for n in (1..self.config.keep).rev() {
+                let from = numbered(n);
+
+                if from.exists() {
+                    fs::rename(from, numbered(n + 1))?;
+                }
+            }
+
+            fs::rename(&path, numbered(1))?;
+        }
+
+        self.output = Self::open_file(&path)?;
+        Ok(())
+    }
+
+    fn needs_rotation(&self, len: usize) -> bool {
+        match self.output {
+            Output::File { size, opened, .. } => {
+                let too_big = self.config.max_size
+                    .is_some_and(|max| size > 0 && size + len as u64 > max);
+
+                let too_old = self.config.max_age
+                    .is_some_and(|max| opened.elapsed().unwrap_or_default() >= max);
+
+                too_big || too_old
+            },
+            Output::Stderr => false
+        }
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if signal::take(libc::SIGUSR1) {
+            self.reopen()?;
+        }
+
+        if self.needs_rotation(line.len()) {
+            self.rotate()?;
+        }
+
+        match self.output {
+            Output::Stderr => io::stderr().write_all(line.as_bytes()),
+            Output::File { ref mut file, ref mut size, .. } => {
+                file.write_all(line.as_bytes())?;
+                *size += line.len() as u64;
+                Ok(())
+            }
+        }
+    }
+}
+
+
+static LOGGER: Mutex<Option<Logger>> = Mutex::new(None);
+
+
+// Start logging with a config, reopening the log file on SIGUSR1
+pub fn init(config: LogConfig) -> io::Result<()> {
+    let logger = Logger::open(config)?;
+
+    if logger.config.file.is_some() {
+        signal::listen(libc::SIGUSR1)?;
+    }
+
+    *LOGGER.lock().unwrap() = Some(logger);
+    Ok(())
+}
+
+pub fn write(level: Level, category: Category, message: fmt::Arguments, fields: &[(&str, &dyn fmt::Display)]) {
+    let mut guard = LOGGER.lock().unwrap();
+
+    let format = match *guard {
+        Some(ref logger) if level > logger.config.level(category) => return,
+        Some(ref logger) => logger.config.format,
+        None if level > Level::Warn => return,
+        None => Format::Text
+    };
+
+    let line = match format {
+        Format::Text => text_line(level, category, message, fields),
+        Format::Json => json_line(level, category, message, fields)
+    };
+
+    let result = match *guard {
+        Some(ref mut logger) => logger.write_line(&line),
+        None => io::stderr().write_all(line.as_bytes())
+    };
+
+    if let Err(e) = result {
+        eprintln!("Error Writing Log: {e}");
+    }
+}
+
+
+fn text_line(level: Level, category: Category, message: fmt::Arguments, fields: &[(&str, &dyn fmt::Display)]) -> String {
+    let mut line = format!("{} {:<5} {}: {message}", timestamp(SystemTime::now()), level.name().to_uppercase(), category.name());
+
+    for (key, value) in fields {
+        line += &format!(" {key}={value}");
+    }
+
+    line.push('\n');
+    line
+}
+
+fn json_line(level: Level, category: Category, message: fmt::Arguments, fields: &[(&str, &dyn fmt::Display)]) -> String {
+    let mut line = format!(
+        "{{\"ts\":\"{}\",\"level\":\"{}\",\"category\":\"{}\",\"msg\":\"{}\"",
+        timestamp(SystemTime::now()),
+        level.name(),
+        category.name(),
+        json_escape(&message.to_string())
+    );
+
+    for (key, value) in fields {
+        line += &format!(",\"{}\":\"{}\"", json_escape(key), json_escape(&value.to_string()));
+    }
+
+    line.push_str("}\n");
+    line
+}
+
+fn json_escape(string: &str) -> String {
+    let mut result = String::with_capacity(string.len());
+
+    for ch in string.chars() {
+        match ch {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if c.is_control() => result += &format!("\\u{:04x}", c as u32),
+            c => result.push(c)
+        }
+    }
+
+    result
+}
+
+// RFC 3339 timestamp in UTC, e.g. 2023-10-08T14:03:11.042Z
+pub fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs / 3600 % 24,
+        secs / 60 % 60,
+        secs % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+// Convert days since 1970-01-01 into a (year, month, day) date
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+
+    (yoe + era * 400 + (month <= 2) as i64, month, day)
+}
+
+
+macro_rules! log {
+    ($level:ident, $category:ident, $($arg:expr),+ $(; $($key:literal => $value:expr),*)?) => {
+        $crate::log::write(
+            $crate::log::Level::$level,
+            $crate::log::Category::$category,
+            format_args!($($arg),+),
+            &[$($(($key, &$value as &dyn std::fmt::Display)),*)?]
+        )
+    };
+}
+
+macro_rules! error {
+    ($category:ident, $($arg:tt)+) => { $crate::log::log!(Error, $category, $($arg)+) };
+}
+
+#[allow(unused_macros)]
+macro_rules! warning {
+    ($category:ident, $($arg:tt)+) => { $crate::log::log!(Warn, $category, $($arg)+) };
+}
+
+macro_rules! info {
+    ($category:ident, $($arg:tt)+) => { $crate::log::log!(Info, $category, $($arg)+) };
+}
+
+macro_rules! debug {
+    ($category:ident, $($arg:tt)+) => { $crate::log::log!(Debug, $category, $($arg)+) };
+}
+
+#[allow(unused_imports)]
+// 'warn' would clash with the builtin attribute
+pub(crate) use {log, error, warning, info, debug};
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_escapes() {
+        assert_eq!(json_escape("plain text"), "plain text");
+        assert_eq!(json_escape("say \"hi\" \\o/"), "say \\\"hi\\\" \\\\o/");
+        assert_eq!(json_escape("a\nb\r\tc"), "a\\nb\\r\\tc");
+        assert_eq!(json_escape("\u{0}\u{1b}[0m\u{7f}"), "\\u0000\\u001b[0m\\u007f");
+        assert_eq!(json_escape("caf\u{e9} \u{1f600}"), "caf\u{e9} \u{1f600}");
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(59), (1970, 3, 1));
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        assert_eq!(civil_from_days(19728), (2024, 1, 6));
+        assert_eq!(civil_from_days(47541), (2100, 3, 1)); // Not a leap year
+
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(timestamp(UNIX_EPOCH + Duration::from_millis(1696773791042)), "2023-10-08T14:03:11.042Z");
+        assert_eq!(timestamp(UNIX_EPOCH + Duration::from_secs(951868799)), "2000-02-29T23:59:59.000Z");
+    }
+
+    #[test]
+    fn rotation() {
+        let dir = std::env::temp_dir().join(format!("ws-log-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("ws.log");
+        let config = LogConfig { file: Some(path.clone()), max_size: Some(10), keep: 2, ..LogConfig::default() };
+        let mut logger = Logger::open(config).unwrap();
+
+        // Each line fills the file, so the next one rotates it
+        for line in ["one\n", "two\n", "three\n", "four\n"] {
+            logger.write_line(&line.repeat(2)).unwrap();
+        }
+
+        let read = |name: &str| fs::read_to_string(dir.join(name)).ok();
+        assert_eq!(read("ws.log").as_deref(), Some("four\nfour\n"));
+        assert_eq!(read("ws.log.1").as_deref(), Some("three\nthree\n"));
+        assert_eq!(read("ws.log.2").as_deref(), Some("two\ntwo\n"));
+        assert_eq!(read("ws.log.3"), None);
+
+        // Keeping nothing discards the old file
+        logger.config.keep = 0;
+        logger.rotate().unwrap();
+        assert_eq!(read("ws.log").as_deref(), Some(""));
+        assert_eq!(read("ws.log.1").as_deref(), Some("three\nthree\n"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,64 +1,290 @@
+mod auth;
+mod cache;
 mod config;
+mod cors;
+mod errors;
+mod headers;
 mod http;
+mod jwt;
+mod log;
+mod mime;
 mod path;
+mod policy;
+mod redirect;
+mod rewrite;
+mod security;
 mod serve;
+mod signal;
+mod systemd;
 
-use config::ServerConfig;
-use http::{response::{Response, ResponseBuilder}, Status};
+use auth::Auth;
+use config::{HostConfig, ListenConfig, ServerConfig};
+use cors::Cors;
+use errors::ErrorPages;
+use headers::HeaderRules;
+use jwt::Jwt;
+use http::{response::{Response, ResponseBuilder}, Connection, Status, Url};
 use path::PathMatch;
+use redirect::Redirects;
+use rewrite::Rewrites;
+use security::SecurityHeaders;
 use serve::ServeDir;
 use httparse::Request;
 use std::{rc::Rc, collections::HashMap};
 
 
-struct State {
+struct Site {
     serve_dir: ServeDir,
-    redirects: HashMap<String, String>,
-    ignored: PathMatch<()>
+    redirects: Redirects,
+    rewrites: Rewrites,
+    ignored: PathMatch<()>,
+    errors: ErrorPages,
+    headers: HeaderRules,
+    cors: Cors,
+    security: SecurityHeaders,
+    auth: Auth,
+    jwt: Jwt
+}
+
+impl From<HostConfig> for Site {
+    fn from(config: HostConfig) -> Self {
+        Site {
+            errors: config.errors.with_dir(&config.dir),
+            rewrites: config.rewrites.with_dir(&config.dir),
+            headers: config.headers,
+            cors: config.cors,
+            security: config.security,
+            auth: config.auth,
+            jwt: config.jwt,
+            serve_dir: ServeDir::new(config.dir, config.routes)
+                .spa_fallback(config.spa)
+                .index_files(config.index)
+                .slash_redirect(config.slash_redirect)
+                .file_policy(config.files)
+                .mime_types(config.mime)
+                .cache(config.cache)
+                .sendfile(config.sendfile)
+                .mmap(config.mmap),
+            redirects: config.redirects,
+            ignored: config.ignored
+        }
+    }
+}
+
+
+struct State {
+    site: Site,
+    site_name: Option<String>,
+    hosts: HashMap<String, Site>,
+    strict_hosts: bool,
+    listeners: Vec<ListenConfig>
+}
+
+impl State {
+    // Select a site by the request's host, falling back to the listener's host, then the default site.
+    // An absolute-form target's host takes precedence over the Host header
+    fn site(&self, conn: &Connection, req: &Request, url: &Url) -> Option<&Site> {
+        let host = url.authority.or_else(|| http::header(req, "Host"))
+            .map(|h| strip_port(h.trim()).to_ascii_lowercase());
+
+        if let Some(ref host) = host {
+            if let Some(site) = self.hosts.get(host) {
+                return Some(site);
+            }
+        }
+
+        if let Some(ref name) = self.listeners[conn.listener].host {
+            return Some(self.hosts.get(name).unwrap_or(&self.site));
+        }
+
+        if !self.strict_hosts || (host.is_some() && host == self.site_name) {
+            Some(&self.site)
+        }
+        else {
+            None
+        }
+    }
+}
+
+
+// Remove the port from a Host header value, e.g. "[::1]:8080" -> "[::1]"
+fn strip_port(host: &str) -> &str {
+    match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host
+    }
 }
 
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let config = ServerConfig::load()?;
+    log::init(config.log)?;
+
+    // Prefer sockets passed in by systemd over binding our own
+    let fds = systemd::listen_fds()?;
+
+    let mut server = match fds.is_empty() {
+        true => http::Server::bind(&config.listeners.iter().map(|l| l.addr.clone()).collect::<Vec<_>>())?,
+        false => http::Server::from_fds(&fds)?
+    }.backend(config.backend);
 
-    println!("Hosting {:?} at \x1b[94mhttp://{:?}\x1b[0m", config.dir, config.address);
+    // Inherited sockets keep the tags of a matching configured address
+    let listeners: Vec<_> = server.addresses()?.into_iter()
+        .map(|addr| match config.listeners.iter().find(|l| l.addr.same_socket(&addr)) {
+            Some(listen) => listen.clone(),
+            None => ListenConfig { addr, behind_tls: false, host: None }
+        })
+        .collect();
+
+    for listen in &listeners {
+        let dir = match listen.host {
+            Some(ref name) => config.hosts.get(name).unwrap_or(&config.site).dir.as_path(),
+            None => config.site.dir.as_path()
+        };
+        let proxy = if listen.behind_tls { " behind TLS" } else { "" };
+
+        println!("Hosting {dir:?} at \x1b[94mhttp://{}\x1b[0m{proxy}", listen.addr);
+    }
+
+    for (name, host) in &config.hosts {
+        println!("Hosting {:?} for \x1b[94m{name}\x1b[0m", host.dir);
+    }
 
     let state = State {
-        serve_dir: ServeDir::new(config.dir, config.routes),
-        redirects: config.redirects,
-        ignored: config.ignored
+        site: config.site.into(),
+        site_name: config.site_name,
+        hosts: config.hosts.into_iter().map(|(name, host)| (name, host.into())).collect(),
+        strict_hosts: config.strict_hosts,
+        listeners
     };
 
-    http::Server::bind(config.address)?
-        .serve_with_state(Box::new(handler), Rc::new(state))?;
+    server.serve_with_state(Box::new(handler), Rc::new(state))?;
 
     Ok(())
 }
 
 
-fn handler(state: Rc<State>, req: Request) -> Option<Response> {
-    match req.method? {
+fn method_not_allowed() -> Response {
+    ResponseBuilder::new()
+        .status(Status::MethodNotAllowed)
+        .header("Allow", "GET")
+        .error_page()
+}
+
+
+fn handler(state: Rc<State>, conn: &Connection, req: Request) -> Option<Response> {
+    let tls = state.listeners[conn.listener].behind_tls;
+
+    let url = match Url::parse(req.path?) {
+        Some(url) => url,
+        None => {
+            let mut res = Response::error(Status::BadRequest);
+            state.site.security.apply(&mut res, tls);
+            return Some(res);
+        }
+    };
+
+    let site = match state.site(conn, &req, &url) {
+        Some(site) => site,
+        None => {
+            let mut res = Response::error(Status::MisdirectedRequest);
+            state.site.security.apply(&mut res, tls);
+            return Some(res);
+        }
+    };
+
+    // Every rule sees the decoded & normalized path, so encoding or '..' can't slip past them
+    let path = match url.normalized_path() {
+        Ok(p) => p,
+        Err(status) => return Some(finish(site, "/", &req, Response::error(status), tls))
+    };
+    let mut user = None;
+
+    let res = match req.method? {
+        "GET" if url.is_asterisk() => Response::error(Status::BadRequest),
         "GET" => {
-            let path = req.path.unwrap();
+            match authorize(site, &path, &req) {
+                Ok(u) => user = u,
+                Err(res) => return Some(finish(site, &path, &req, res, tls))
+            }
 
-            if let Some(redir) = state.redirects.get(path) {
-                Some(ResponseBuilder::new()
-                    .status(Status::TemporaryRedirect)
-                    .header("Location", redir)
-                    .into_response())
+            if let Some(res) = site.redirects.get(&path, &url) {
+                res
             }
-            else if state.ignored.contains(path) {
-                None
+            else if site.ignored.contains(&path) {
+                return None;
             }
             else {
-                Some(state.serve_dir.serve(path))
+                match site.rewrites.apply(&path, &url, &req) {
+                    Some(rewritten) => serve_rewritten(site, &url, &rewritten, &req, &mut user)?,
+                    None => site.serve_dir.serve(&url, &req)
+                }
             }
         },
-        _ => {
-            Some(ResponseBuilder::new()
-                .status(Status::MethodNotAllowed)
-                .header("Allow", "GET")
-                .into_response())
-        }
+        "OPTIONS" if url.is_asterisk() => ResponseBuilder::new()
+            .status(Status::NoContent)
+            .header("Allow", "GET, OPTIONS")
+            .into_response(),
+        "OPTIONS" => match site.cors.preflight(&path, &req) {
+            Some(mut res) => {
+                site.security.apply(&mut res, tls);
+                site.headers.apply(&path, &mut res);
+                return Some(res);
+            },
+            None => method_not_allowed()
+        },
+        _ => method_not_allowed()
+    };
+
+    let mut res = finish(site, &path, &req, res, tls);
+    res.set_user(user);
+
+    Some(res)
+}
+
+
+// Check auth & JWT rules for a normalized path, giving the user if any, or the response to send instead
+fn authorize(site: &Site, path: &str, req: &Request) -> Result<Option<String>, Response> {
+    let user = site.auth.check(path, req)?;
+
+    match site.jwt.check(path, req)? {
+        Some(subject) => Ok(Some(subject)),
+        None => Ok(user)
+    }
+}
+
+
+// Serve a rewritten target, checking it like the original path so rewrites can't reach protected or ignored files
+fn serve_rewritten(site: &Site, url: &Url, target: &str, req: &Request, user: &mut Option<String>) -> Option<Response> {
+    let target = match Url::parse(target) {
+        Some(target) => target,
+        None => return Some(Response::error(Status::InternalServerError))
+    };
+    let path = match target.normalized_path() {
+        Ok(p) => p,
+        Err(status) => return Some(Response::error(status))
+    };
+
+    match authorize(site, &path, req) {
+        Ok(Some(u)) => *user = Some(u),
+        Ok(None) => (),
+        Err(res) => return Some(res)
+    }
+
+    if site.ignored.contains(&path) {
+        return None;
     }
+
+    Some(site.serve_dir.serve_rewritten(&target, url, req))
+}
+
+
+// Apply error pages & extra headers to a response
+fn finish(site: &Site, path: &str, req: &Request, res: Response, tls: bool) -> Response {
+    let mut res = site.errors.apply(path, res);
+    site.security.apply(&mut res, tls);
+    site.headers.apply(path, &mut res);
+    site.cors.apply(path, req, &mut res);
+
+    res
 }
@@ -0,0 +1,410 @@
+// MIME types by file extension
+
+use crate::path::compile_glob;
+use regex::Regex;
+use std::{cell::RefCell, collections::HashMap, fs, io, path::{Path, PathBuf}, time::SystemTime};
+
+
+const BUILTIN: &[(&str, &str)] = &[
+    // Text
+    ("htm", "text/html"),
+    ("html", "text/html"),
+    ("xhtml", "application/xhtml+xml"),
+    ("css", "text/css"),
+    ("js", "text/javascript"),
+    ("mjs", "text/javascript"),
+    ("cjs", "text/javascript"),
+    ("txt", "text/plain"),
+    ("text", "text/plain"),
+    ("log", "text/plain"),
+    ("conf", "text/plain"),
+    ("ini", "text/plain"),
+    ("md", "text/markdown"),
+    ("markdown", "text/markdown"),
+    ("csv", "text/csv"),
+    ("tsv", "text/tab-separated-values"),
+    ("ics", "text/calendar"),
+    ("vcf", "text/vcard"),
+    ("vtt", "text/vtt"),
+    ("rtf", "application/rtf"),
+    ("appcache", "text/cache-manifest"),
+
+    // Data
+    ("json", "application/json"),
+    ("jsonld", "application/ld+json"),
+    ("map", "application/json"),
+    ("webmanifest", "application/manifest+json"),
+    ("geojson", "application/geo+json"),
+    ("xml", "application/xml"),
+    ("xsl", "application/xml"),
+    ("xslt", "application/xslt+xml"),
+    ("rss", "application/rss+xml"),
+    ("atom", "application/atom+xml"),
+    ("yaml", "application/yaml"),
+    ("yml", "application/yaml"),
+    ("toml", "application/toml"),
+    ("wasm", "application/wasm"),
+
+    // Images
+    ("png", "image/png"),
+    ("apng", "image/apng"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("jpe", "image/jpeg"),
+    ("jfif", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("heic", "image/heic"),
+    ("heif", "image/heif"),
+    ("jxl", "image/jxl"),
+    ("svg", "image/svg+xml"),
+    ("svgz", "image/svg+xml"),
+    ("ico", "image/x-icon"),
+    ("cur", "image/x-icon"),
+    ("bmp", "image/bmp"),
+    ("tif", "image/tiff"),
+    ("tiff", "image/tiff"),
+    ("psd", "image/vnd.adobe.photoshop"),
+
+    // Fonts
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("ttc", "font/collection"),
+    ("eot", "application/vnd.ms-fontobject"),
+
+    // Audio
+    ("mp3", "audio/mpeg"),
+    ("m4a", "audio/mp4"),
+    ("aac", "audio/aac"),
+    ("oga", "audio/ogg"),
+    ("ogg", "audio/ogg"),
+    ("opus", "audio/opus"),
+    ("wav", "audio/wav"),
+    ("weba", "audio/webm"),
+    ("flac", "audio/flac"),
+    ("mid", "audio/midi"),
+    ("midi", "audio/midi"),
+
+    // Video
+    ("mp4", "video/mp4"),
+    ("m4v", "video/mp4"),
+    ("mpeg", "video/mpeg"),
+    ("mpg", "video/mpeg"),
+    ("webm", "video/webm"),
+    ("ogv", "video/ogg"),
+    ("mov", "video/quicktime"),
+    ("avi", "video/x-msvideo"),
+    ("mkv", "video/x-matroska"),
+    ("3gp", "video/3gpp"),
+    ("ts", "video/mp2t"),
+    ("m3u8", "application/vnd.apple.mpegurl"),
+    ("mpd", "application/dash+xml"),
+
+    // Documents
+    ("pdf", "application/pdf"),
+    ("epub", "application/epub+zip"),
+    ("doc", "application/msword"),
+    ("docx", "application/vnd.openxmlformats-officedocument.wordprocessingml.document"),
+    ("xls", "application/vnd.ms-excel"),
+    ("xlsx", "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"),
+    ("ppt", "application/vnd.ms-powerpoint"),
+    ("pptx", "application/vnd.openxmlformats-officedocument.presentationml.presentation"),
+    ("odt", "application/vnd.oasis.opendocument.text"),
+    ("ods", "application/vnd.oasis.opendocument.spreadsheet"),
+    ("odp", "application/vnd.oasis.opendocument.presentation"),
+
+    // Archives & binaries
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tgz", "application/gzip"),
+    ("bz2", "application/x-bzip2"),
+    ("xz", "application/x-xz"),
+    ("zst", "application/zstd"),
+    ("br", "application/x-brotli"),
+    ("tar", "application/x-tar"),
+    ("7z", "application/x-7z-compressed"),
+    ("rar", "application/vnd.rar"),
+    ("jar", "application/java-archive"),
+    ("apk", "application/vnd.android.package-archive"),
+    ("deb", "application/vnd.debian.binary-package"),
+    ("rpm", "application/x-rpm"),
+    ("dmg", "application/x-apple-diskimage"),
+    ("iso", "application/x-iso9660-image"),
+    ("exe", "application/vnd.microsoft.portable-executable"),
+    ("msi", "application/x-msi"),
+    ("bin", "application/octet-stream"),
+    ("pem", "application/x-pem-file"),
+    ("crt", "application/x-x509-ca-cert"),
+    ("der", "application/x-x509-ca-cert")
+];
+
+// Non-"text/" types that are text, and get a charset too
+const TEXT_TYPES: &[&str] = &[
+    "application/javascript",
+    "application/json",
+    "application/ld+json",
+    "application/manifest+json",
+    "application/geo+json",
+    "application/xml",
+    "application/xhtml+xml",
+    "application/xslt+xml",
+    "application/rss+xml",
+    "application/atom+xml",
+    "application/yaml",
+    "application/toml",
+    "image/svg+xml"
+];
+
+// Leading bytes of common binary formats
+const MAGIC: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"BM", "image/bmp"),
+    (b"\x00\x00\x01\x00", "image/x-icon"),
+    (b"II*\x00", "image/tiff"),
+    (b"MM\x00*", "image/tiff"),
+    (b"%PDF-", "application/pdf"),
+    (b"\x00asm", "application/wasm"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"BZh", "application/x-bzip2"),
+    (b"\xfd7zXZ\x00", "application/x-xz"),
+    (b"\x28\xb5\x2f\xfd", "application/zstd"),
+    (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (b"Rar!\x1a\x07", "application/vnd.rar"),
+    (b"wOFF", "font/woff"),
+    (b"wOF2", "font/woff2"),
+    (b"OTTO", "font/otf"),
+    (b"\x00\x01\x00\x00\x00", "font/ttf"),
+    (b"OggS", "audio/ogg"),
+    (b"fLaC", "audio/flac"),
+    (b"ID3", "audio/mpeg"),
+    (b"\x1a\x45\xdf\xa3", "video/webm"),
+    (b"\x7fELF", "application/octet-stream")
+];
+
+// Bytes inspected when sniffing
+pub const SNIFF_LEN: usize = 512;
+
+// Sniffed files remembered before the cache is cleared
+const SNIFF_CACHE_SIZE: usize = 4096;
+
+pub const SYSTEM_FILE: &str = "/etc/mime.types";
+
+// A file's modification time when sniffed, & its type
+type Sniffed = (Option<SystemTime>, Option<&'static str>);
+
+
+// Guess a type from a file's leading bytes
+fn sniff(data: &[u8]) -> Option<&'static str> {
+    let data = &data[..data.len().min(SNIFF_LEN)];
+
+    if let Some((_, mime)) = MAGIC.iter().find(|(magic, _)| data.starts_with(magic)) {
+        return Some(mime);
+    }
+
+    // RIFF containers & ISO media have their type after a header
+    match (data.get(..4), data.get(8..12)) {
+        (Some(b"RIFF"), Some(b"WEBP")) => return Some("image/webp"),
+        (Some(b"RIFF"), Some(b"WAVE")) => return Some("audio/wav"),
+        (Some(b"RIFF"), Some(b"AVI ")) => return Some("video/x-msvideo"),
+        (_, Some(brand)) if data.get(4..8) == Some(b"ftyp") => return match brand {
+            b"avif" | b"avis" => Some("image/avif"),
+            b"heic" | b"heix" | b"mif1" => Some("image/heic"),
+            b"qt  " => Some("video/quicktime"),
+            b"M4A " => Some("audio/mp4"),
+            _ => Some("video/mp4")
+        },
+        _ => {}
+    }
+
+    let text = data.trim_ascii_start();
+    let starts_with = |prefix: &str| text.len() >= prefix.len() && text[..prefix.len()].eq_ignore_ascii_case(prefix.as_bytes());
+
+    if starts_with("<!doctype html") || starts_with("<html") {
+        Some("text/html")
+    }
+    else if starts_with("<svg") {
+        Some("image/svg+xml")
+    }
+    else if starts_with("<?xml") {
+        Some("application/xml")
+    }
+    else if is_text(data) {
+        Some("text/plain")
+    }
+    else {
+        None
+    }
+}
+
+// Whether data looks like UTF-8 text, allowing a character cut off at the end
+fn is_text(data: &[u8]) -> bool {
+    let valid = match std::str::from_utf8(data) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none() && data.len() == SNIFF_LEN
+    };
+
+    valid && !data.iter().any(|&b| b < 0x20 && !b"\t\n\r\x0c".contains(&b))
+}
+
+
+#[derive(Debug)]
+pub struct MimeTypes {
+    types: HashMap<String, String>, // Built-in & system types, by lowercase extension
+    overrides: HashMap<String, String>, // From the [mime] section
+    forced: Vec<(Regex, String)>, // Path patterns & their types
+    pub default: Option<String>,
+    pub sniff: bool, // Inspect files with unknown extensions
+    sniffed: RefCell<HashMap<PathBuf, Sniffed>>
+}
+
+impl MimeTypes {
+    pub fn new() -> Self {
+        MimeTypes {
+            types: BUILTIN.iter().map(|(ext, mime)| (ext.to_string(), mime.to_string())).collect(),
+            overrides: HashMap::new(),
+            forced: vec![],
+            default: Some("application/octet-stream".into()),
+            sniff: true,
+            sniffed: RefCell::new(HashMap::new())
+        }
+    }
+
+    // Load a mime.types file, with lines like "image/png png"
+    pub fn load_system<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let source = fs::read_to_string(path)?;
+
+        for line in source.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+            let mut parts = line.split_whitespace();
+            let mime = parts.next().unwrap_or("");
+
+            for ext in parts {
+                self.types.insert(ext.to_ascii_lowercase(), mime.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    // Set the type for an extension (e.g. ".svg" or "svg")
+    pub fn set<S: Into<String>>(&mut self, ext: &str, mime: S) {
+        self.overrides.insert(ext.trim_start_matches('.').to_ascii_lowercase(), mime.into());
+    }
+
+    // Force a type for paths matching a glob or '~' regex, whatever their extension
+    pub fn force<S: Into<String>>(&mut self, pattern: &str, mime: S) -> Result<(), String> {
+        self.forced.push((compile_glob(pattern)?, mime.into()));
+        Ok(())
+    }
+
+    // Get the type for a file's extension, without any default
+    pub fn for_path(&self, path: &Path) -> Option<&str> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+
+        self.overrides.get(&ext)
+            .or_else(|| self.types.get(&ext))
+            .map(String::as_str)
+    }
+
+    // Sniff a file's type from its data, reusing earlier results while it's unchanged
+    fn sniff_cached(&self, path: &Path, data: &[u8]) -> Option<&'static str> {
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+        let mut cache = self.sniffed.borrow_mut();
+
+        if let Some((time, mime)) = cache.get(path) {
+            if modified.is_some() && *time == modified {
+                return *mime;
+            }
+        }
+
+        if cache.len() >= SNIFF_CACHE_SIZE {
+            cache.clear();
+        }
+
+        let mime = sniff(data);
+        cache.insert(path.to_owned(), (modified, mime));
+        mime
+    }
+
+    // Get the Content-Type for a file served at 'url', with a charset for text types
+    pub fn content_type(&self, url: &str, path: &Path, data: &[u8]) -> Option<String> {
+        let mime = self.forced.iter()
+            .find(|(pattern, _)| pattern.is_match(url))
+            .map(|(_, mime)| mime.as_str())
+            .or_else(|| self.for_path(path))
+            .or_else(|| self.sniff.then(|| self.sniff_cached(path, data)).flatten())
+            .or(self.default.as_deref())?;
+
+        Some(with_charset(mime))
+    }
+}
+
+
+// Add "charset=utf-8" to text types that don't have a charset
+fn with_charset(mime: &str) -> String {
+    let essence = mime.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    let is_text = essence.starts_with("text/") || TEXT_TYPES.contains(&essence.as_str());
+
+    match is_text && !mime.to_ascii_lowercase().contains("charset=") {
+        true => format!("{mime}; charset=utf-8"),
+        false => mime.to_string()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn magic() {
+        // No signature hides behind an earlier one
+        for (magic, mime) in MAGIC {
+            let data = [magic, &[0u8; 16][..]].concat();
+            assert_eq!(sniff(&data), Some(*mime), "{magic:?}");
+        }
+
+        assert_eq!(sniff(b"RIFF\x24\x00\x00\x00WEBPVP8 "), Some("image/webp"));
+        assert_eq!(sniff(b"RIFF\x24\x00\x00\x00WAVEfmt "), Some("audio/wav"));
+        assert_eq!(sniff(b"\x00\x00\x00\x1cftypavif\x00\x00"), Some("image/avif"));
+        assert_eq!(sniff(b"\x00\x00\x00\x18ftypisom\x00\x00"), Some("video/mp4"));
+        assert_eq!(sniff(b"RIFF\x24\x00\x00\x00"), None);
+    }
+
+    #[test]
+    fn markup() {
+        assert_eq!(sniff(b"\n  <!DOCTYPE html><html>"), Some("text/html"));
+        assert_eq!(sniff(b"<HTML lang=\"en\">"), Some("text/html"));
+        assert_eq!(sniff(b"<svg xmlns=\"http://www.w3.org/2000/svg\">"), Some("image/svg+xml"));
+        assert_eq!(sniff(b"<?xml version=\"1.0\"?><feed>"), Some("application/xml"));
+        assert_eq!(sniff(b"<"), Some("text/plain"));
+    }
+
+    #[test]
+    fn text() {
+        assert_eq!(sniff(b"plain text\r\n\twith a \x0c form feed"), Some("text/plain"));
+        assert_eq!(sniff("caf\u{e9} \u{1f600}".as_bytes()), Some("text/plain"));
+        assert_eq!(sniff(b""), Some("text/plain"));
+        assert_eq!(sniff(b"text\x00with a nul"), None);
+        assert_eq!(sniff(b"\x1b[31mred"), None);
+        assert_eq!(sniff(b"latin-1 caf\xe9!"), None);
+
+        // A character cut off by the sniffing limit is still text, but not one ending the file
+        let mut cut = vec![b'a'; SNIFF_LEN - 1];
+        cut.extend("\u{e9}".as_bytes());
+        assert_eq!(sniff(&cut), Some("text/plain"));
+        assert!(is_text(&cut[..SNIFF_LEN]));
+        assert!(!is_text(&[b'a', 0xc3]));
+
+        // Invalid bytes aren't excused by the limit
+        let mut invalid = vec![b'a'; SNIFF_LEN - 1];
+        invalid.push(0xff);
+        assert!(!is_text(&invalid));
+    }
+}
@@ -1,70 +1,351 @@
 // Directory & File router
 
-use std::{collections::HashMap, path::{Path, PathBuf}};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use regex::{Captures, Regex};
+use std::{collections::HashMap, path::{Path, PathBuf}, sync::LazyLock};
+
+
+// Characters escaped when decoded text goes back into a URL, so '%', '?' or line breaks stay part of the path
+const ENCODE: &AsciiSet = &CONTROLS.add(b' ').add(b'"').add(b'#').add(b'%').add(b'<').add(b'>').add(b'?').add(b'`').add(b'{').add(b'}');
+
+// "$1", "${name}" or "$$" in a target
+static REFERENCE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\$(?:\$|\{(\w+)\}|(\w+))").unwrap());
+
+
+// Compile "~regex" or a glob into a regex, each glob wildcard becoming a capture group.
+// Plain paths give None
+pub fn compile_pattern(pattern: &str) -> Option<Result<Regex, String>> {
+    let source = match pattern.strip_prefix('~') {
+        Some(re) => re.to_string(),
+        None if pattern.contains(['*', '?']) => {
+            let mut re = String::from("^");
+            let mut rest = pattern;
+
+            while !rest.is_empty() {
+                if let Some(r) = rest.strip_prefix("**/") {
+                    re += "(?:(.*)/)?";
+                    rest = r;
+                }
+                else if let Some(r) = rest.strip_prefix("**") {
+                    re += "(.*)";
+                    rest = r;
+                }
+                else if let Some(r) = rest.strip_prefix('*') {
+                    re += "([^/]*)";
+                    rest = r;
+                }
+                else if let Some(r) = rest.strip_prefix('?') {
+                    re += "([^/])";
+                    rest = r;
+                }
+                else {
+                    let len = rest.chars().next().map_or(1, char::len_utf8);
+                    re += &regex::escape(&rest[..len]);
+                    rest = &rest[len..];
+                }
+            }
+
+            re + "$"
+        },
+        None => return None
+    };
+
+    Some(Regex::new(&source).map_err(|e| format!("Invalid pattern '{pattern}': {e}")))
+}
+
+// Percent-encode part of a decoded path for use in a URL
+pub fn encode(path: &str) -> String {
+    utf8_percent_encode(path, ENCODE).to_string()
+}
+
+// Expand captures of a decoded path into a URL target, encoding them
+pub fn expand(caps: &Captures, target: &str) -> String {
+    REFERENCE.replace_all(target, |r: &Captures| {
+        let name = match r.get(1).or_else(|| r.get(2)) {
+            Some(name) => name.as_str(),
+            None => return "$".to_string()
+        };
+
+        let capture = match name.parse::<usize>() {
+            Ok(i) => caps.get(i),
+            Err(_) => caps.name(name)
+        };
+
+        capture.map_or(String::new(), |c| encode(c.as_str()))
+    }).into_owned()
+}
+
+// Like compile_pattern, but a plain path gives a regex matching only itself
+pub fn compile_glob(pattern: &str) -> Result<Regex, String> {
+    compile_pattern(pattern).unwrap_or_else(|| {
+        Regex::new(&format!("^{}$", regex::escape(pattern))).map_err(|e| format!("Invalid pattern '{pattern}': {e}"))
+    })
+}
+
+
+// How a path was matched
+pub enum Match<'a, 'p, V> {
+    Exact(&'a V),
+    Prefix(&'a V, &'p Path), // Remainder of the path after the prefix
+    Pattern(&'a V, Captures<'p>)
+}
 
 
 #[derive(Debug)]
 pub struct PathMatch<V> {
     dirs: HashMap<PathBuf, V>,
-    files: HashMap<PathBuf, V>
+    files: HashMap<PathBuf, V>,
+    patterns: Vec<(Regex, V)> // Tried in order
 }
 
 impl PathMatch<PathBuf> {
-    pub fn add(&mut self, from: PathBuf, to: PathBuf) {
+    pub fn add(&mut self, from: &str, to: PathBuf) -> Result<(), String> {
         // Use 'to' instead of 'from', because the directory at 'from' might not exist
-        if to.is_dir() {
-            self.dirs.insert(from, to);
+        let dir = to.is_dir();
+        self.insert(from, dir, to)
+    }
+
+    pub fn get<P: AsRef<Path>>(&self, path: P) -> Option<PathBuf> {
+        match self.find(path.as_ref())? {
+            Match::Exact(to) => Some(to.clone()),
+            Match::Prefix(to, rest) => Some(to.join(rest)),
+            Match::Pattern(to, caps) => {
+                let mut expanded = String::new();
+                caps.expand(&to.to_string_lossy(), &mut expanded);
+                Some(expanded.into())
+            }
         }
-        else {
-            self.files.insert(from, to);
+    }
+}
+
+impl PathMatch<()> {
+    pub fn add(&mut self, path: &str, dir: bool) -> Result<(), String> {
+        self.insert(path, dir, ())
+    }
+
+    pub fn contains<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.find(path.as_ref()).is_some()
+    }
+}
+
+impl<V> PathMatch<V> {
+    pub fn new() -> Self {
+        PathMatch {
+            dirs: HashMap::new(),
+            files: HashMap::new(),
+            patterns: vec![]
+        }
+    }
+
+    // Add a plain path, a glob like "/assets/**/*.map", or a regex starting with '~'
+    pub fn insert(&mut self, key: &str, dir: bool, value: V) -> Result<(), String> {
+        match compile_pattern(key) {
+            Some(re) => self.patterns.push((re?, value)),
+            None if dir => { self.dirs.insert(key.into(), value); },
+            None => { self.files.insert(key.into(), value); }
         }
+
+        Ok(())
     }
 
-    pub fn get(&self, path: &PathBuf) -> Option<PathBuf> {
-        if let Some(to) = self.files.get(path) {
-            return Some(to.clone());
+    // Exact matches win over the longest directory prefix, which wins over the first matching pattern
+    pub fn find<'p>(&self, path: &'p Path) -> Option<Match<'_, 'p, V>> {
+        if let Some(value) = self.files.get(path) {
+            return Some(Match::Exact(value));
         }
 
         for parent in path.ancestors() {
-            if let Some(to) = self.dirs.get(parent) {
-                return Some(to.join(path.file_name().unwrap()));
+            if let Some(value) = self.dirs.get(parent) {
+                return Some(Match::Prefix(value, path.strip_prefix(parent).unwrap_or(path)));
             }
         }
 
-        None
+        let path = path.to_str()?;
+
+        self.patterns.iter()
+            .find_map(|(re, value)| re.captures(path).map(|caps| Match::Pattern(value, caps)))
     }
 }
 
-impl PathMatch<()> {
-    pub fn add(&mut self, path: PathBuf, dir: bool) {
-        if dir {
-            self.dirs.insert(path, ());
-        }
-        else {
-            self.files.insert(path, ());
+
+// Resolve '.' & '..' in a decoded URL path, giving a path relative to the served directory,
+// or None if it would escape it or contains a NUL byte
+pub fn normalize(path: &str) -> Option<PathBuf> {
+    if path.contains('\0') {
+        return None;
+    }
+
+    let mut segments = vec![];
+
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {},
+            ".." => { segments.pop()?; },
+            _ => segments.push(segment)
         }
     }
 
-    pub fn contains<P: AsRef<Path>>(&self, path: P) -> bool {
-        self.files.contains_key(path.as_ref()) || {
+    Some(segments.iter().collect())
+}
 
-            for parent in path.as_ref().ancestors() {
-                if self.dirs.contains_key(parent) {
-                    return true;
-                }
-            }
 
-            false
-        }
+// Normalize a decoded URL path, keeping it absolute & keeping any trailing slash,
+// e.g. "//a/./b/../c/" -> "/a/c/"
+pub fn normalize_url(path: &str) -> Option<String> {
+    let normalized = normalize(path)?;
+    let mut url = format!("/{}", normalized.to_str()?);
+
+    if path.ends_with('/') && url.len() > 1 {
+        url.push('/');
     }
+
+    Some(url)
 }
 
-impl<V> PathMatch<V> {
+// Whether a URL path is inside a prefix, only matching whole segments so "/a" covers "/a/b" but not "/ab"
+pub fn in_prefix(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+
+    path.strip_prefix(prefix).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+
+// Values for URL path prefixes, looked up by the longest prefix a path is in
+#[derive(Debug, Clone)]
+pub struct Prefixes<V>(Vec<(String, V)>);
+
+impl<V> Prefixes<V> {
     pub fn new() -> Self {
-        PathMatch {
-            dirs: HashMap::new(),
-            files: HashMap::new()
+        Prefixes(vec![])
+    }
+
+    // Get the value for a prefix, adding one from 'default' if it's new
+    pub fn entry(&mut self, prefix: &str, default: impl FnOnce() -> V) -> &mut V {
+        let index = match self.0.iter().position(|(p, _)| p == prefix) {
+            Some(i) => i,
+            None => {
+                self.0.push((prefix.to_string(), default()));
+                self.0.len() - 1
+            }
+        };
+
+        &mut self.0[index].1
+    }
+
+    pub fn insert(&mut self, prefix: &str, value: V) {
+        match self.0.iter_mut().find(|(p, _)| p == prefix) {
+            Some((_, old)) => *old = value,
+            None => self.0.push((prefix.to_string(), value))
         }
     }
 
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &V)> {
+        self.0.iter().map(|(prefix, value)| (prefix.as_str(), value))
+    }
+
+    // The value of the longest prefix 'path' is in
+    pub fn get(&self, path: &str) -> Option<&V> {
+        self.find(path, Some)
+    }
+
+    // What 'f' gives for the longest prefix 'path' is in that it gives anything for
+    pub fn find<'a, T>(&'a self, path: &str, f: impl Fn(&'a V) -> Option<T>) -> Option<T> {
+        self.0.iter()
+            .filter(|(prefix, _)| in_prefix(path, prefix))
+            .filter_map(|(prefix, value)| Some((prefix.trim_end_matches('/').len(), f(value)?)))
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, found)| found)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_paths() {
+        assert_eq!(normalize("/a/b"), Some("a/b".into()));
+        assert_eq!(normalize("//a/./b/"), Some("a/b".into()));
+        assert_eq!(normalize("/a/../b"), Some("b".into()));
+        assert_eq!(normalize("/a/b/../../c"), Some("c".into()));
+        assert_eq!(normalize("/"), Some("".into()));
+        assert_eq!(normalize("/.."), None);
+        assert_eq!(normalize("/a/../../b"), None);
+        assert_eq!(normalize("/a\0.txt"), None);
+
+        // Paths are decoded first, so '%2e%2e' & '..%2f' arrive as '..' & anything still encoded is a plain name
+        assert_eq!(normalize("/../etc/passwd"), None);
+        assert_eq!(normalize("/%2e%2e/etc"), Some("%2e%2e/etc".into()));
+    }
+
+    #[test]
+    fn normalize_urls() {
+        assert_eq!(normalize_url("/a/b").as_deref(), Some("/a/b"));
+        assert_eq!(normalize_url("//a/./b/../c/").as_deref(), Some("/a/c/"));
+        assert_eq!(normalize_url("/").as_deref(), Some("/"));
+        assert_eq!(normalize_url("/a/..").as_deref(), Some("/"));
+        assert_eq!(normalize_url("/a/../").as_deref(), Some("/"));
+        assert_eq!(normalize_url("/../a"), None);
+    }
+
+    #[test]
+    fn prefixes() {
+        assert!(in_prefix("/a", "/a"));
+        assert!(in_prefix("/a/", "/a"));
+        assert!(in_prefix("/a/b", "/a/"));
+        assert!(in_prefix("/a", "/a/"));
+        assert!(in_prefix("/anything", "/"));
+        assert!(!in_prefix("/ab", "/a"));
+        assert!(!in_prefix("/b/a", "/a"));
+    }
+
+    #[test]
+    fn prefix_tables() {
+        let mut prefixes = Prefixes::new();
+        prefixes.insert("/", 0);
+        prefixes.insert("/a/", 1);
+        prefixes.insert("/a/b", 2);
+        *prefixes.entry("/c", || 3) += 10;
+
+        assert_eq!(prefixes.get("/x"), Some(&0));
+        assert_eq!(prefixes.get("/a"), Some(&1));
+        assert_eq!(prefixes.get("/ab"), Some(&0));
+        assert_eq!(prefixes.get("/a/b/c"), Some(&2));
+        assert_eq!(prefixes.get("/c/d"), Some(&13));
+
+        // Prefixes 'find' gives nothing for are skipped
+        assert_eq!(prefixes.find("/a/b", |v| (v % 2 == 1).then_some(*v)), Some(1));
+        assert_eq!(Prefixes::<u8>::new().get("/"), None);
+    }
+
+    #[test]
+    fn patterns() {
+        assert!(compile_pattern("/plain/path").is_none());
+        assert!(compile_pattern("~^/(").unwrap().is_err());
+
+        let star = compile_pattern("/assets/*.css").unwrap().unwrap();
+        assert!(star.is_match("/assets/site.css"));
+        assert!(!star.is_match("/assets/a/site.css"));
+        assert_eq!(&star.captures("/assets/site.css").unwrap()[1], "site");
+
+        let any = compile_pattern("/assets/**/*.map").unwrap().unwrap();
+        assert!(any.is_match("/assets/app.map"));
+        assert!(any.is_match("/assets/a/b/app.map"));
+        assert!(!any.is_match("/other/app.map"));
+
+        let one = compile_pattern("/v?/api").unwrap().unwrap();
+        assert!(one.is_match("/v1/api"));
+        assert!(!one.is_match("/v10/api"));
+        assert!(!one.is_match("/v//api"));
+
+        // Regex characters in globs are literal
+        let dots = compile_pattern("/a.b/*").unwrap().unwrap();
+        assert!(dots.is_match("/a.b/c"));
+        assert!(!dots.is_match("/axb/c"));
+
+        let plain = compile_glob("/exact").unwrap();
+        assert!(plain.is_match("/exact"));
+        assert!(!plain.is_match("/exact/more"));
+    }
 }
@@ -0,0 +1,163 @@
+// Symlink & hidden file policies
+
+use crate::path::Prefixes;
+use std::{fs, os::unix::fs::MetadataExt, path::Path, str::FromStr};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Symlinks {
+    Follow,
+    FollowIfOwnerMatches, // The link & its target have the same owner
+    WithinRootOnly,
+    Never
+}
+
+impl FromStr for Symlinks {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "follow" => Ok(Symlinks::Follow),
+            "follow-if-owner-matches" => Ok(Symlinks::FollowIfOwnerMatches),
+            "within-root-only" => Ok(Symlinks::WithinRootOnly),
+            "never" => Ok(Symlinks::Never),
+            _ => Err(format!("Unknown symlink policy '{s}'"))
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hidden {
+    Allow,
+    Deny, // 403
+    Ignore // 404, as if the file didn't exist
+}
+
+impl FromStr for Hidden {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(Hidden::Allow),
+            "deny" => Ok(Hidden::Deny),
+            "ignore" => Ok(Hidden::Ignore),
+            _ => Err(format!("Unknown hidden file policy '{s}'"))
+        }
+    }
+}
+
+
+// Options set for one path prefix, unset ones come from shorter prefixes
+#[derive(Debug, Default)]
+struct Rule {
+    symlinks: Option<Symlinks>,
+    hidden: Option<Hidden>
+}
+
+
+#[derive(Debug)]
+pub struct FilePolicy {
+    rules: Prefixes<Rule>
+}
+
+impl FilePolicy {
+    pub fn new() -> Self {
+        FilePolicy { rules: Prefixes::new() }
+    }
+
+    // Set "symlinks" or "hidden" for a path prefix
+    pub fn set(&mut self, prefix: &str, option: &str, value: &str) -> Result<(), String> {
+        let rule = self.rules.entry(prefix, Rule::default);
+
+        match option {
+            "symlinks" => rule.symlinks = Some(value.parse()?),
+            "hidden" => rule.hidden = Some(value.parse()?),
+            _ => return Err(format!("Unknown file policy option '{option}'"))
+        }
+
+        Ok(())
+    }
+
+    pub fn symlinks(&self, path: &str) -> Symlinks {
+        self.rules.find(path, |r| r.symlinks).unwrap_or(Symlinks::WithinRootOnly)
+    }
+
+    pub fn hidden(&self, path: &str) -> Hidden {
+        self.rules.find(path, |r| r.hidden).unwrap_or(Hidden::Deny)
+    }
+}
+
+
+// Whether any segment of a relative path is hidden, besides ".well-known" which is meant to be public
+pub fn is_hidden(path: &Path) -> bool {
+    path.iter()
+        .filter_map(|s| s.to_str())
+        .any(|s| s.starts_with('.') && s != ".well-known")
+}
+
+// Whether every link between 'root' & 'root/path' may be followed.
+// 'canonical_root' is used for links that must stay within the root
+pub fn links_allowed(root: &Path, canonical_root: &Path, path: &Path, policy: Symlinks) -> bool {
+    if policy == Symlinks::Follow {
+        return true;
+    }
+
+    let mut current = root.to_owned();
+
+    for segment in path.iter() {
+        current.push(segment);
+
+        let link = match fs::symlink_metadata(&current) {
+            Ok(meta) if meta.file_type().is_symlink() => meta,
+            Ok(_) => continue,
+            Err(_) => return true // Missing files are a 404 later
+        };
+
+        let allowed = match policy {
+            Symlinks::Follow => true,
+            Symlinks::Never => false,
+            Symlinks::FollowIfOwnerMatches => fs::metadata(&current).is_ok_and(|target| target.uid() == link.uid()),
+            Symlinks::WithinRootOnly => fs::canonicalize(&current).is_ok_and(|target| target.starts_with(canonical_root))
+        };
+
+        if !allowed {
+            return false;
+        }
+    }
+
+    true
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefixes() {
+        let mut policy = FilePolicy::new();
+        policy.set("/pub", "hidden", "allow").unwrap();
+        policy.set("/pub/private", "hidden", "ignore").unwrap();
+        policy.set("/pub/private", "symlinks", "never").unwrap();
+        policy.set("/", "symlinks", "follow").unwrap();
+
+        assert_eq!(policy.hidden("/pub/.env"), Hidden::Allow);
+        assert_eq!(policy.hidden("/pub"), Hidden::Allow);
+        assert_eq!(policy.hidden("/public/.env"), Hidden::Deny);
+        assert_eq!(policy.hidden("/pub/private/.env"), Hidden::Ignore);
+
+        // Unset options come from shorter prefixes
+        assert_eq!(policy.symlinks("/pub/x"), Symlinks::Follow);
+        assert_eq!(policy.symlinks("/pub/private/x"), Symlinks::Never);
+        assert_eq!(FilePolicy::new().symlinks("/x"), Symlinks::WithinRootOnly);
+    }
+
+    #[test]
+    fn hidden_paths() {
+        assert!(is_hidden(Path::new(".env")));
+        assert!(is_hidden(Path::new("a/.git/config")));
+        assert!(!is_hidden(Path::new(".well-known/security.txt")));
+        assert!(!is_hidden(Path::new("a/b.txt")));
+    }
+}
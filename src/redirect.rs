@@ -0,0 +1,229 @@
+// URL redirects
+
+use crate::{path::{encode, expand, Match, PathMatch}, http::{response::{Response, ResponseBuilder}, Status, Url}};
+use std::collections::HashMap;
+
+
+// Redirects followed when checking for loops, before giving up
+const MAX_HOPS: usize = 10;
+
+
+#[derive(Debug)]
+pub struct Redirect {
+    target: String, // May contain "$1" or "${name}" captures
+    status: Status,
+    keep_query: bool
+}
+
+impl Redirect {
+    // Parse "<target> [301|302|303|307|308] [prefix] [keep-query|drop-query]", returning whether it's a prefix rule
+    fn parse(value: &str) -> Result<(Self, bool), String> {
+        let mut parts = value.split_whitespace();
+        let target = parts.next().ok_or("Missing redirect target")?;
+
+        let mut redirect = Redirect { target: target.into(), status: Status::TemporaryRedirect, keep_query: true };
+        let mut prefix = false;
+
+        for option in parts {
+            match option {
+                "301" => redirect.status = Status::MovedPermanently,
+                "302" => redirect.status = Status::Found,
+                "303" => redirect.status = Status::SeeOther,
+                "307" => redirect.status = Status::TemporaryRedirect,
+                "308" => redirect.status = Status::PermanentRedirect,
+                "prefix" => prefix = true,
+                "keep-query" => redirect.keep_query = true,
+                "drop-query" => redirect.keep_query = false,
+                _ => return Err(format!("Unknown redirect option '{option}'"))
+            }
+        }
+
+        Ok((redirect, prefix))
+    }
+
+    fn is_external(&self) -> bool {
+        self.target.contains("://") || self.target.starts_with("//")
+    }
+}
+
+
+#[derive(Debug)]
+pub struct Redirects {
+    exact: HashMap<String, Redirect>, // Kept as strings, so "/docs" & "/docs/" are different sources
+    rules: PathMatch<Redirect>, // Prefixes & patterns
+    sources: Vec<String> // Plain paths & prefixes, for loop detection
+}
+
+impl Redirects {
+    pub fn new() -> Self {
+        Redirects { exact: HashMap::new(), rules: PathMatch::new(), sources: vec![] }
+    }
+
+    // Add a rule from a path, glob or "~regex" to a target with options.
+    // Prefixes are matched by directory, so "/old" covers "/old/x" but not "/older"
+    pub fn add(&mut self, from: &str, value: &str) -> Result<(), String> {
+        let (redirect, prefix) = Redirect::parse(value)?;
+
+        if from.starts_with('~') || from.contains(['*', '?']) {
+            return self.rules.insert(from, prefix, redirect);
+        }
+
+        self.sources.push(from.to_string());
+
+        match prefix {
+            true => self.rules.insert(from, prefix, redirect),
+            false => {
+                self.exact.insert(from.to_string(), redirect);
+                Ok(())
+            }
+        }
+    }
+
+    // Where to send a request for 'path'
+    fn location(&self, path: &str) -> Option<(&Redirect, String)> {
+        if let Some(redirect) = self.exact.get(path) {
+            return Some((redirect, redirect.target.clone()));
+        }
+
+        let (redirect, location) = match self.rules.find(path.as_ref())? {
+            Match::Exact(r) => (r, r.target.clone()),
+            Match::Prefix(r, rest) => match rest.as_os_str().is_empty() {
+                true => (r, r.target.clone()),
+                false => {
+                    let slash = if path.ends_with('/') { "/" } else { "" };
+                    (r, format!("{}/{}{slash}", r.target.trim_end_matches('/'), encode(&rest.to_string_lossy())))
+                }
+            },
+            Match::Pattern(r, caps) => (r, expand(&caps, &r.target))
+        };
+
+        Some((redirect, location))
+    }
+
+    // Build the redirect response for a request's normalized path, if any rule matches
+    pub fn get(&self, path: &str, url: &Url) -> Option<Response> {
+        let (redirect, location) = self.location(path)?;
+
+        let location = match redirect.keep_query {
+            true => url.with_query(&location),
+            false => location
+        };
+
+        Some(ResponseBuilder::new()
+            .status(redirect.status)
+            .header("Location", location)
+            .into_response())
+    }
+
+    // Follow each plain rule's local targets, failing if they lead back to themselves
+    pub fn check_loops(&self) -> Result<(), String> {
+        for source in &self.sources {
+            let mut chain = vec![source.clone()];
+
+            while let Some((redirect, location)) = self.location(chain.last().unwrap()) {
+                if redirect.is_external() {
+                    break;
+                }
+
+                let next = location.split('?').next().unwrap_or("").to_string();
+                let looped = chain.contains(&next);
+                chain.push(next);
+
+                if looped || chain.len() > MAX_HOPS {
+                    return Err(format!("Redirect loop: {}", chain.join(" -> ")));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::testing::path_variants;
+
+    fn location(redirects: &Redirects, path: &str) -> Option<String> {
+        redirects.location(path).map(|(_, location)| location)
+    }
+
+    #[test]
+    fn trailing_slash() {
+        let mut redirects = Redirects::new();
+        redirects.add("/docs", "/docs/").unwrap();
+
+        assert!(redirects.check_loops().is_ok());
+        assert_eq!(location(&redirects, "/docs").as_deref(), Some("/docs/"));
+        assert_eq!(location(&redirects, "/docs/"), None);
+    }
+
+    #[test]
+    fn prefixes_and_patterns() {
+        let mut redirects = Redirects::new();
+        redirects.add("/old", "/new 301 prefix").unwrap();
+        redirects.add("/blog/*/*.html", "/posts/$1/$2").unwrap();
+
+        assert_eq!(location(&redirects, "/old").as_deref(), Some("/new"));
+        assert_eq!(location(&redirects, "/old/a/b").as_deref(), Some("/new/a/b"));
+        assert_eq!(location(&redirects, "/older"), None);
+        assert_eq!(location(&redirects, "/blog/2024/hello.html").as_deref(), Some("/posts/2024/hello"));
+    }
+
+    #[test]
+    fn normalized_requests() {
+        let mut redirects = Redirects::new();
+        redirects.add("/docs", "/docs/ 301").unwrap();
+
+        for target in path_variants("/docs") {
+            let target = format!("{target}?v=1");
+            let url = Url::parse(&target).unwrap();
+            let res = redirects.get(&url.normalized_path().unwrap(), &url).unwrap();
+
+            assert_eq!(res.status(), Status::MovedPermanently);
+            assert!(String::from_utf8(res.into_bytes().unwrap()).unwrap().contains("Location: /docs/?v=1\r\n"));
+        }
+    }
+
+    #[test]
+    fn encoded_locations() {
+        let mut redirects = Redirects::new();
+        redirects.add("/old-docs", "/docs/ prefix").unwrap();
+        redirects.add("/files/*", "/store/$1").unwrap();
+
+        let get = |target: &str| {
+            let url = Url::parse(target).unwrap();
+            let res = redirects.get(&url.normalized_path().unwrap(), &url).unwrap();
+            String::from_utf8(res.into_bytes().unwrap()).unwrap()
+        };
+
+        // Decoded line breaks, spaces & '?' must not reach the header raw
+        let res = get("/old-docs/%0d%0aSet-Cookie:%20pwned=1");
+        assert!(res.contains("Location: /docs/%0D%0ASet-Cookie:%20pwned=1\r\n"));
+        assert!(!res.contains("\r\nSet-Cookie"));
+
+        assert!(get("/old-docs/a%20b%3F.txt?q=1").contains("Location: /docs/a%20b%3F.txt?q=1\r\n"));
+        assert!(get("/old-docs/dir/").contains("Location: /docs/dir/\r\n"));
+        assert!(get("/files/%0d%0aX:%20y%3F").contains("Location: /store/%0D%0AX:%20y%3F\r\n"));
+    }
+
+    #[test]
+    fn loops() {
+        let mut redirects = Redirects::new();
+        redirects.add("/a", "/b").unwrap();
+        redirects.add("/b", "/c?x=1").unwrap();
+        assert!(redirects.check_loops().is_ok());
+
+        redirects.add("/c", "/a").unwrap();
+        assert_eq!(redirects.check_loops(), Err("Redirect loop: /a -> /b -> /c -> /a".into()));
+
+        let mut redirects = Redirects::new();
+        redirects.add("/x", "/x/y prefix").unwrap();
+        assert!(redirects.check_loops().is_err());
+
+        let mut redirects = Redirects::new();
+        redirects.add("/ext", "https://example.com/ext").unwrap();
+        assert!(redirects.check_loops().is_ok());
+    }
+}
@@ -0,0 +1,150 @@
+// Internal URL rewrites, invisible to the client
+
+use crate::{log, path::{compile_glob, expand}, http::{self, Url}};
+use httparse::Request;
+use regex::Regex;
+use std::path::{Path, PathBuf};
+
+
+#[derive(Debug)]
+enum Condition {
+    Method(Vec<String>),
+    Header(String, Option<Regex>), // Present, or matching a regex
+    Query(String, Option<Regex>), // A query parameter, like Header
+    File // The requested path exists in the served directory
+}
+
+impl Condition {
+    // Parse "method=GET,HEAD", "header=Name", "header=Name~regex", "query=name~regex", "file" or "!file",
+    // where a leading '!' negates any condition
+    fn parse(value: &str) -> Result<(Self, bool), String> {
+        let (negate, value) = match value.strip_prefix('!') {
+            Some(v) => (true, v),
+            None => (false, value)
+        };
+
+        let condition = match value.split_once('=') {
+            Some(("method", methods)) => Condition::Method(methods.split(',').map(str::to_ascii_uppercase).collect()),
+            Some(("header", header)) => match header.split_once('~') {
+                Some((name, re)) => Condition::Header(
+                    name.into(),
+                    Some(Regex::new(re).map_err(|e| format!("Invalid header regex '{re}': {e}"))?)
+                ),
+                None => Condition::Header(header.into(), None)
+            },
+            Some(("query", param)) => match param.split_once('~') {
+                Some((name, re)) => Condition::Query(
+                    name.into(),
+                    Some(Regex::new(re).map_err(|e| format!("Invalid query regex '{re}': {e}"))?)
+                ),
+                None => Condition::Query(param.into(), None)
+            },
+            None if value == "file" => Condition::File,
+            _ => return Err(format!("Unknown rewrite condition '{value}'"))
+        };
+
+        Ok((condition, negate))
+    }
+
+    fn holds(&self, dir: &Path, path: &str, url: &Url, req: &Request) -> bool {
+        match self {
+            Condition::Method(methods) => req.method.is_some_and(|m| methods.iter().any(|n| n == m)),
+            Condition::Header(name, re) => match (http::header(req, name), re) {
+                (Some(value), Some(re)) => re.is_match(value),
+                (value, None) => value.is_some(),
+                (None, _) => false
+            },
+            Condition::Query(name, re) => match (url.param(name), re) {
+                (Some(value), Some(re)) => re.is_match(&value),
+                (value, None) => value.is_some(),
+                (None, _) => false
+            },
+            Condition::File => dir.join(path.trim_start_matches('/')).exists()
+        }
+    }
+}
+
+
+#[derive(Debug)]
+struct Rule {
+    pattern: Regex,
+    target: String, // May contain "$1" or "${name}" captures
+    conditions: Vec<(Condition, bool)> // And whether each is negated
+}
+
+
+#[derive(Debug)]
+pub struct Rewrites {
+    dir: PathBuf,
+    rules: Vec<Rule> // Tried in order, the first match wins
+}
+
+impl Rewrites {
+    pub fn new() -> Self {
+        Rewrites {
+            dir: PathBuf::new(),
+            rules: vec![]
+        }
+    }
+
+    // Add a rule from a path, glob or "~regex" to "<target> [conditions...]"
+    pub fn add(&mut self, from: &str, value: &str) -> Result<(), String> {
+        let mut parts = value.split_whitespace();
+        let target = parts.next().ok_or("Missing rewrite target")?;
+
+        self.rules.push(Rule {
+            pattern: compile_glob(from)?,
+            target: target.into(),
+            conditions: parts.map(Condition::parse).collect::<Result<_, _>>()?
+        });
+
+        Ok(())
+    }
+
+    // Set the directory file conditions are checked in
+    pub fn with_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.dir = dir.as_ref().to_owned();
+        self
+    }
+
+    // Rewrite a request target by its normalized path, keeping its query, or None if no rule applies
+    pub fn apply(&self, path: &str, url: &Url, req: &Request) -> Option<String> {
+        let (rule, caps) = self.rules.iter().find_map(|rule| {
+            let caps = rule.pattern.captures(path)?;
+
+            rule.conditions.iter()
+                .all(|(c, negate)| c.holds(&self.dir, path, url, req) != *negate)
+                .then_some((rule, caps))
+        })?;
+
+        let rewritten = url.with_query(&expand(&caps, &rule.target));
+
+        log::debug!(Serve, "Rewrote Path"; "from" => path, "to" => rewritten);
+        Some(rewritten)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::testing;
+
+    fn rewrite(rewrites: &Rewrites, target: &str) -> Option<String> {
+        let req = testing::get(target);
+        let url = Url::parse(target).unwrap();
+        rewrites.apply(&url.normalized_path().unwrap(), &url, &req)
+    }
+
+    #[test]
+    fn decoded_paths() {
+        let mut rewrites = Rewrites::new();
+        rewrites.add(r"~^/blog/(\d+)$", "/posts/$1.html").unwrap();
+        rewrites.add("/files/*", "/store/$1").unwrap();
+
+        assert_eq!(rewrite(&rewrites, "/blog/%31").as_deref(), Some("/posts/1.html"));
+        assert_eq!(rewrite(&rewrites, "/x/../blog/2?page=3").as_deref(), Some("/posts/2.html?page=3"));
+        assert_eq!(rewrite(&rewrites, "/files/100%25%3F.txt").as_deref(), Some("/store/100%25%3F.txt"));
+        assert_eq!(rewrite(&rewrites, "/blog/x"), None);
+    }
+}
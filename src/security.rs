@@ -0,0 +1,92 @@
+// Security header presets
+
+use crate::http::response::Response;
+use std::str::FromStr;
+
+
+const BASIC: &[(&str, &str)] = &[
+    ("Strict-Transport-Security", "max-age=31536000"),
+    ("Content-Security-Policy", "frame-ancestors 'self'"),
+    ("X-Content-Type-Options", "nosniff"),
+    ("Referrer-Policy", "strict-origin-when-cross-origin"),
+    ("Cross-Origin-Opener-Policy", "same-origin-allow-popups")
+];
+
+const STRICT: &[(&str, &str)] = &[
+    ("Strict-Transport-Security", "max-age=63072000; includeSubDomains; preload"),
+    ("Content-Security-Policy", "default-src 'self'; object-src 'none'; base-uri 'self'; form-action 'self'; frame-ancestors 'none'"),
+    ("X-Content-Type-Options", "nosniff"),
+    ("Referrer-Policy", "no-referrer"),
+    ("Permissions-Policy", "camera=(), microphone=(), geolocation=(), payment=(), usb=()"),
+    ("Cross-Origin-Opener-Policy", "same-origin"),
+    ("Cross-Origin-Embedder-Policy", "require-corp"),
+    ("Cross-Origin-Resource-Policy", "same-origin")
+];
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preset {
+    Off,
+    Basic,
+    Strict
+}
+
+impl Preset {
+    fn headers(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Preset::Off => &[],
+            Preset::Basic => BASIC,
+            Preset::Strict => STRICT
+        }
+    }
+}
+
+impl FromStr for Preset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Preset::Off),
+            "basic" => Ok(Preset::Basic),
+            "strict" => Ok(Preset::Strict),
+            _ => Err(format!("Unknown security preset '{s}'"))
+        }
+    }
+}
+
+
+#[derive(Debug)]
+pub struct SecurityHeaders {
+    pub preset: Preset,
+    overrides: Vec<(String, String)> // An empty value removes the header
+}
+
+impl SecurityHeaders {
+    pub fn new() -> Self {
+        SecurityHeaders {
+            preset: Preset::Off,
+            overrides: vec![]
+        }
+    }
+
+    pub fn set<S: Into<String>>(&mut self, header: S, value: S) {
+        self.overrides.push((header.into(), value.into()));
+    }
+
+    pub fn apply(&self, res: &mut Response, tls: bool) {
+        let preset = self.preset.headers().iter().map(|(k, v)| (*k, *v));
+        let overrides = self.overrides.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+
+        for (header, value) in preset.chain(overrides) {
+            // HSTS is ignored by browsers over plain HTTP, and could mislead a proxy
+            if header.eq_ignore_ascii_case("Strict-Transport-Security") && !tls {
+                continue;
+            }
+
+            match value.is_empty() {
+                true => res.remove_header(header),
+                false => res.set_header(header, value)
+            }
+        }
+    }
+}
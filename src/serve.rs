@@ -1,83 +1,459 @@
 // Struct for serving static files
 
-use crate::{path::PathMatch, http::{response::{ResponseBuilder, Response}, Status}};
-use percent_encoding::percent_decode_str;
-use std::{path::{Path, PathBuf}, io, fs};
+use crate::{log, cache::{self, CacheConfig, File, FileCache}, mime::{MimeTypes, SNIFF_LEN}, path::{normalize, PathMatch}, policy::{self, FilePolicy, Hidden}, http::{self, response::{FileBody, ResponseBuilder, Response}, Status, Url}};
+use httparse::Request;
+use memmap2::Mmap;
+use std::{cell::RefCell, collections::HashMap, path::{Path, PathBuf}, io, fs, os::unix::fs::{FileExt, MetadataExt}, rc::{Rc, Weak}};
 
 
-fn mime_from_path(path: &Path) -> Option<&str> {
-    match path.extension()?.to_str()? {
-        "htm" | "html" => Some("text/html"),
-        "css" => Some("text/css"),
-        "js" => Some("text/javascript"),
-        "mjs" => Some("application/javascript"),
+// Whether an Accept header ranks HTML at least as high as anything else
+fn prefers_html(accept: &str) -> bool {
+    let mut html = 0.0;
+    let mut other = 0.0;
 
-        "png" => Some("image/png"),
-        "jpg" | "jpeg" => Some("image/jpeg"),
-        "ico" => Some("image/x-icon"),
+    for item in accept.split(',') {
+        let mut params = item.split(';');
+        let mime = params.next().unwrap_or("").trim();
 
-        _ => None
+        let q = params
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        match mime {
+            "text/html" | "application/xhtml+xml" => html = f32::max(html, q),
+            "*/*" => {},
+            _ => other = f32::max(other, q)
+        }
     }
+
+    html > 0.0 && html >= other
+}
+
+
+// Whether an If-None-Match header lists 'etag', or is "*"
+fn etag_matches(tags: &str, etag: &str) -> bool {
+    tags.split(',')
+        .map(|t| t.trim().trim_start_matches("W/"))
+        .any(|t| t == "*" || t == etag)
+}
+
+// Whether an Accept-Encoding header allows gzip
+fn accepts_gzip(accept: &str) -> bool {
+    accept.split(',').any(|item| {
+        let mut params = item.split(';');
+        let coding = params.next().unwrap_or("").trim();
+        let q = params.find_map(|p| p.trim().strip_prefix("q=")).and_then(|q| q.parse::<f32>().ok()).unwrap_or(1.0);
+
+        (coding.eq_ignore_ascii_case("gzip") || coding == "*") && q > 0.0
+    })
+}
+
+
+// A file's contents, or an open file to send straight from disk
+enum Body {
+    Loaded(Rc<File>),
+    Streamed { body: FileBody, etag: String, prefix: Vec<u8> } // 'prefix' is for sniffing its type
+}
+
+
+// The start of a file, for sniffing its type
+fn read_prefix(file: &fs::File, len: u64) -> io::Result<Vec<u8>> {
+    let mut prefix = vec![0; SNIFF_LEN.min(len as usize)];
+    let read = file.read_at(&mut prefix, 0)?;
+
+    prefix.truncate(read);
+    Ok(prefix)
+}
+
+// Whether two metadata are of the same, unchanged file
+fn same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    a.dev() == b.dev() && a.ino() == b.ino() && a.len() == b.len() && a.modified().ok() == b.modified().ok()
 }
 
+type SharedMap = (Weak<fs::File>, Weak<Mmap>);
+
 
 pub struct ServeDir {
     path: PathBuf,
-    routes: PathMatch<PathBuf>
+    root: PathBuf, // Canonical 'path'
+    routes: PathMatch<PathBuf>,
+    spa: Option<PathBuf>,
+    index: Vec<String>, // Tried in order
+    slash_redirect: Option<Status>,
+    policy: FilePolicy,
+    mime: MimeTypes,
+    cache: Option<FileCache>,
+    sendfile: Option<u64>, // Smallest file sent from disk rather than memory
+    mmap: Option<u64>, // Smallest file mapped instead, takes priority over 'sendfile'
+    maps: RefCell<HashMap<PathBuf, SharedMap>> // Mappings still being sent
 }
 
 impl ServeDir {
     pub fn new<P: AsRef<Path>>(path: P, routes: PathMatch<PathBuf>) -> Self {
         ServeDir {
             path: path.as_ref().to_owned(),
-            routes
+            root: fs::canonicalize(&path).unwrap_or_else(|_| path.as_ref().to_owned()),
+            routes,
+            spa: None,
+            index: vec!["index.html".into()],
+            slash_redirect: Some(Status::MovedPermanently),
+            policy: FilePolicy::new(),
+            mime: MimeTypes::new(),
+            cache: None,
+            sendfile: Some(64 << 10),
+            mmap: None,
+            maps: RefCell::new(HashMap::new())
+        }
+    }
+
+    pub fn index_files(mut self, index: Vec<String>) -> Self {
+        self.index = index;
+        self
+    }
+
+    // Redirect "/dir" to "/dir/" with 'status', or serve it as is if None
+    pub fn slash_redirect(mut self, status: Option<Status>) -> Self {
+        self.slash_redirect = status;
+        self
+    }
+
+    pub fn file_policy(mut self, policy: FilePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    // Serve 'file' instead of 404 to HTML requests for paths that aren't assets
+    pub fn spa_fallback(mut self, file: Option<PathBuf>) -> Self {
+        self.spa = file;
+        self
+    }
+
+    pub fn mime_types(mut self, mime: MimeTypes) -> Self {
+        self.mime = mime;
+        self
+    }
+
+    // Keep files in memory, loading the served directory now if the config asks
+    pub fn cache(mut self, config: Option<CacheConfig>) -> Self {
+        self.cache = config.map(|config| {
+            let warm = config.warm;
+            let cache = FileCache::new(config);
+
+            if warm {
+                cache.warm(&self.path);
+            }
+
+            cache
+        });
+        self
+    }
+
+    // Send files of at least 'min' bytes that aren't cached straight from disk, or never if None
+    pub fn sendfile(mut self, min: Option<u64>) -> Self {
+        self.sendfile = min;
+        self
+    }
+
+    // Map uncached files of at least 'min' bytes into memory, or never if None
+    pub fn mmap(mut self, min: Option<u64>) -> Self {
+        self.mmap = min;
+        self
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Body> {
+        let meta = fs::metadata(path)?;
+        let len = meta.len();
+        let cached = self.cache.as_ref().is_some_and(|cache| cache.holds(len));
+
+        if self.mmap.is_some_and(|min| len >= min) && len > 0 && !cached {
+            return self.map(path, &meta);
+        }
+
+        if self.sendfile.is_some_and(|min| len >= min) && !cached {
+            let file = fs::File::open(path)?;
+            let meta = file.metadata()?;
+
+            return Ok(Body::Streamed {
+                etag: cache::etag(meta.len(), meta.modified().ok()),
+                prefix: read_prefix(&file, meta.len())?,
+                body: FileBody { file: Rc::new(file), len: meta.len(), map: None }
+            });
         }
+
+        match self.cache {
+            Some(ref cache) => cache.get(path).map(Body::Loaded),
+            None => File::load(path, false).map(|file| Body::Loaded(Rc::new(file)))
+        }
+    }
+
+    // Map a file, reusing the mapping of responses still sending it if the file hasn't changed since
+    fn map(&self, path: &Path, meta: &fs::Metadata) -> io::Result<Body> {
+        let mut maps = self.maps.borrow_mut();
+
+        let shared = maps.get(path)
+            .and_then(|(file, map)| Some((file.upgrade()?, map.upgrade()?)))
+            .filter(|(file, _)| file.metadata().is_ok_and(|m| same_file(&m, meta)));
+
+        let (file, map) = match shared {
+            Some(shared) => shared,
+            None => {
+                let file = fs::File::open(path)?;
+
+                // Safety: the writer checks the file's length before touching the mapping,
+                // changes to the contents only affect what's sent
+                let map = unsafe { Mmap::map(&file)? };
+                let (file, map) = (Rc::new(file), Rc::new(map));
+
+                maps.retain(|_, (file, _)| file.strong_count() > 0);
+                maps.insert(path.to_owned(), (Rc::downgrade(&file), Rc::downgrade(&map)));
+                (file, map)
+            }
+        };
+
+        let len = map.len() as u64;
+        let modified = file.metadata()?.modified().ok();
+
+        Ok(Body::Streamed {
+            etag: cache::etag(len, modified),
+            prefix: read_prefix(&file, len)?,
+            body: FileBody { file, len, map: Some(map) }
+        })
+    }
+
+    fn use_spa_fallback(&self, file_path: &Path, req: &Request) -> bool {
+        // Only known non-HTML types are assets, "/users/jane.doe" is still a route
+        let is_asset = self.mime.for_path(file_path).is_some_and(|mime| mime != "text/html");
+
+        self.spa.is_some()
+            && req.method == Some("GET")
+            && !is_asset
+            && http::header(req, "Accept").is_some_and(prefers_html)
     }
 
-    pub fn serve(&self, path: &str) -> Response {
-        let path = match percent_decode_str(path.trim_start_matches('/')).decode_utf8() {
-            Ok(p) => p,
-            Err(_) => return ResponseBuilder::new()
-                .status(Status::BadRequest)
-                .body("Bad Request")
+    fn file_response(&self, url: &str, file_path: &Path, body: Body, req: &Request) -> Response {
+        let (etag, data) = match body {
+            Body::Loaded(ref file) => (&file.etag, &file.data[..]),
+            Body::Streamed { ref etag, ref prefix, .. } => (etag, &prefix[..])
         };
 
-        let mut file_path = self.path.clone();
-        file_path.push(path.as_ref());
+        let mut res = ResponseBuilder::new()
+            .status(Status::Ok)
+            .header("ETag", etag);
 
-        // Auto-request 'index.html' for directory requests
-        if file_path.is_dir() {
-            file_path.push("index.html");
+        if let Some(mime) = self.mime.content_type(url, file_path, data) {
+            res = res.header("Content-Type", mime);
         }
 
-        // Reroute files
-        if let Some(route) = self.routes.get(&file_path) {
-            file_path = route;
+        if http::header(req, "If-None-Match").is_some_and(|tags| etag_matches(tags, etag)) {
+            return res.status(Status::NotModified).into_response();
         }
 
-        match fs::read(&file_path) {
-            Ok(data) => {
-                let mut res = ResponseBuilder::new()
-                    .status(Status::Ok);
+        let file = match body {
+            Body::Loaded(file) => file,
+            Body::Streamed { body, .. } => return res.file(body)
+        };
+
+        // Only buffered files have a compressed copy
+        match file.gzip {
+            Some(ref gzip) => {
+                res = res.header("Vary", "Accept-Encoding");
 
-                if let Some(mime) = mime_from_path(&file_path) {
-                    res = res.header("Content-Type", mime);
+                match http::header(req, "Accept-Encoding").is_some_and(accepts_gzip) {
+                    true => res.header("Content-Encoding", "gzip").shared_body(gzip.clone()),
+                    false => res.shared_body(file.data.clone())
                 }
+            },
+            None => res.shared_body(file.data.clone())
+        }
+    }
+
+    pub fn serve(&self, url: &Url, req: &Request) -> Response {
+        self.serve_rewritten(url, url, req)
+    }
+
+    // Serve 'target' for a request to 'url', which trailing-slash redirects are built from so rewrites stay hidden
+    pub fn serve_rewritten(&self, target: &Url, url: &Url, req: &Request) -> Response {
+        let path = match target.decoded_path() {
+            Some(p) => p,
+            None => return Response::error(Status::BadRequest)
+        };
+
+        // Resolve '..' before anything sees the path, so it can't climb out of the root
+        let path = match normalize(&path) {
+            Some(p) => p,
+            None => {
+                log::warning!(Serve, "Path Escapes Root"; "path" => path);
+                return Response::error(Status::Forbidden);
+            }
+        };
+
+        let full_path = Path::new("/").join(&path);
+        let url_path = full_path.to_string_lossy();
+
+        if policy::is_hidden(&path) {
+            match self.policy.hidden(&url_path) {
+                Hidden::Allow => {},
+                Hidden::Deny => return Response::error(Status::Forbidden),
+                Hidden::Ignore => return Response::error(Status::NotFound)
+            }
+        }
+
+        // Reroute files, route targets are trusted as configured
+        let route = self.routes.get(&full_path);
+        let mut file_path = match &route {
+            Some(route) => route.clone(),
+            None => self.path.join(&path)
+        };
+
+        // Serve the first index file that exists for directory requests
+        let is_dir = file_path.is_dir();
 
-                res.body(data)
+        if is_dir {
+            let index = self.index.iter().find(|i| file_path.join(i).is_file()).or(self.index.first());
+            file_path.push(index.map_or("index.html", String::as_str));
+        }
+
+        // Symlinks can still lead outside the root
+        if route.is_none() {
+            let relative = file_path.strip_prefix(&self.path).unwrap_or(&file_path);
+
+            if !policy::links_allowed(&self.path, &self.root, relative, self.policy.symlinks(&url_path)) {
+                log::warning!(Serve, "Symlink Not Allowed"; "path" => file_path.display());
+                return Response::error(Status::Forbidden);
+            }
+        }
+
+        // Relative links in a directory's index only work from "/dir/"
+        if let Some(status) = self.slash_redirect.filter(|_| is_dir && !url.path.ends_with('/')) {
+            return ResponseBuilder::new()
+                .status(status)
+                .header("Location", url.with_query(&format!("{}/", url.path)))
+                .into_response();
+        }
+
+        match self.read(&file_path) {
+            Ok(body) => self.file_response(&url_path, &file_path, body, req),
+            Err(e) if e.kind() == io::ErrorKind::NotFound && self.use_spa_fallback(&file_path, req) => {
+                let spa = self.spa.as_ref().unwrap();
+
+                match self.read(&self.path.join(spa)) {
+                    Ok(body) => self.file_response(&format!("/{}", spa.display()), spa, body, req),
+                    Err(_) => Response::error(Status::NotFound)
+                }
             },
             Err(e) if e.kind() == io::ErrorKind::NotFound => {
-                ResponseBuilder::new()
-                    .status(Status::NotFound)
-                    .body("Not Found")
+                Response::error(Status::NotFound)
             },
             Err(e) => {
-                eprintln!("Error Serving Path: {e}");
+                log::error!(Serve, "Error Serving Path: {e}"; "path" => file_path.display());
 
-                ResponseBuilder::new()
-                    .status(Status::InternalServerError)
-                    .body("Internal Server Error")
+                Response::error(Status::InternalServerError)
             }
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::testing;
+    use std::os::unix::fs::symlink;
+
+    // A served directory "web" with a file next to it that must stay unreachable
+    fn setup(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ws-serve-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        fs::create_dir_all(dir.join("web/sub")).unwrap();
+        fs::write(dir.join("web/a.txt"), "a").unwrap();
+        fs::write(dir.join("web/sub/b.txt"), "b").unwrap();
+        fs::write(dir.join("outside.txt"), "outside").unwrap();
+        symlink(dir.join("outside.txt"), dir.join("web/link.txt")).unwrap();
+        symlink(&dir, dir.join("web/up")).unwrap();
+        symlink(dir.join("web/a.txt"), dir.join("web/inside.txt")).unwrap();
+
+        dir
+    }
+
+    fn status(serve: &ServeDir, target: &str) -> Status {
+        serve.serve(&Url::parse(target).unwrap(), &testing::get(target)).status()
+    }
+
+    #[test]
+    fn traversal() {
+        let dir = setup("traversal");
+        let serve = ServeDir::new(dir.join("web"), PathMatch::new());
+        let outside = dir.join("outside.txt");
+
+        assert_eq!(status(&serve, "/a.txt"), Status::Ok);
+        assert_eq!(status(&serve, "/sub/../a.txt"), Status::Ok);
+        assert_eq!(status(&serve, "/../outside.txt"), Status::Forbidden);
+        assert_eq!(status(&serve, "/sub/../../outside.txt"), Status::Forbidden);
+        assert_eq!(status(&serve, "/%2e%2e/outside.txt"), Status::Forbidden);
+        assert_eq!(status(&serve, "/%2E%2E/outside.txt"), Status::Forbidden);
+        assert_eq!(status(&serve, "/..%2foutside.txt"), Status::Forbidden);
+        assert_eq!(status(&serve, "/sub%2f..%2f..%2foutside.txt"), Status::Forbidden);
+        assert_eq!(status(&serve, "/a.txt%00"), Status::Forbidden);
+        assert_eq!(status(&serve, "/a.txt%00.html"), Status::Forbidden);
+        assert_eq!(status(&serve, "/%ff"), Status::BadRequest);
+        assert_eq!(status(&serve, &format!("/{}", outside.display())), Status::NotFound);
+        assert_eq!(status(&serve, "//etc/passwd"), Status::NotFound);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn symlinks() {
+        let dir = setup("symlinks");
+        let serve = ServeDir::new(dir.join("web"), PathMatch::new());
+
+        assert_eq!(status(&serve, "/inside.txt"), Status::Ok);
+        assert_eq!(status(&serve, "/link.txt"), Status::Forbidden);
+        assert_eq!(status(&serve, "/up/outside.txt"), Status::Forbidden);
+        assert_eq!(status(&serve, "/up/web/a.txt"), Status::Forbidden);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn spa_fallback() {
+        let dir = setup("spa");
+        fs::write(dir.join("web/index.html"), "<!doctype html>").unwrap();
+
+        let serve = ServeDir::new(dir.join("web"), PathMatch::new()).spa_fallback(Some("index.html".into()));
+        let html = |target: &str| {
+            serve.serve(&Url::parse(target).unwrap(), &testing::request("GET", target, &[("Accept", "text/html,*/*;q=0.8")])).status()
+        };
+
+        assert_eq!(html("/settings/profile"), Status::Ok);
+        assert_eq!(html("/users/jane.doe"), Status::Ok);
+        assert_eq!(html("/old/page.html"), Status::Ok);
+        assert_eq!(html("/app.js"), Status::NotFound);
+        assert_eq!(html("/logo.png"), Status::NotFound);
+
+        // Only for requests preferring HTML
+        assert_eq!(status(&serve, "/settings/profile"), Status::NotFound);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rewritten_slash_redirects() {
+        let dir = setup("rewritten");
+        let serve = ServeDir::new(dir.join("web"), PathMatch::new());
+
+        let location = |target: &str, url: &str| {
+            let res = serve.serve_rewritten(&Url::parse(target).unwrap(), &Url::parse(url).unwrap(), &testing::get(url));
+            String::from_utf8(res.into_bytes().unwrap()).unwrap()
+        };
+
+        // Redirects point at what the client asked for, not the internal target
+        assert!(location("/sub", "/alias?x=1").contains("Location: /alias/?x=1\r\n"));
+        assert!(location("/sub", "/sub").contains("Location: /sub/\r\n"));
+        assert!(location("/sub", "/alias/").starts_with("HTTP/1.1 404"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
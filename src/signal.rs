@@ -0,0 +1,46 @@
+// Process signal flags
+
+use std::{io, sync::atomic::{AtomicBool, Ordering}};
+
+
+const MAX_SIGNAL: usize = 32;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const UNSET: AtomicBool = AtomicBool::new(false);
+static RECEIVED: [AtomicBool; MAX_SIGNAL] = [UNSET; MAX_SIGNAL];
+
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    if let Some(flag) = RECEIVED.get(signal as usize) {
+        flag.store(true, Ordering::SeqCst);
+    }
+}
+
+
+// Start recording a signal instead of running its default action
+pub fn listen(signal: libc::c_int) -> io::Result<()> {
+    if signal as usize >= MAX_SIGNAL {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unsupported Signal {signal}")));
+    }
+
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+
+        if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+// Check whether a signal was received since the last call, and reset it
+pub fn take(signal: libc::c_int) -> bool {
+    match RECEIVED.get(signal as usize) {
+        Some(flag) => flag.swap(false, Ordering::SeqCst),
+        None => false
+    }
+}
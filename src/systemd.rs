@@ -0,0 +1,164 @@
+// systemd socket activation & service notifications
+
+use crate::log;
+use std::{env, io, os::unix::{io::RawFd, net::UnixDatagram}, process, time::Duration};
+
+
+// First file descriptor passed by the service manager
+const LISTEN_FDS_START: RawFd = 3;
+
+
+// Check that a variable was meant for this process, as opposed to a parent
+fn for_this_process(var: &str) -> bool {
+    match env::var(var) {
+        Ok(pid) => pid.parse() == Ok(process::id()),
+        Err(_) => true // Not set, assume ours
+    }
+}
+
+
+// How many sockets LISTEN_PID & LISTEN_FDS pass to process 'pid', or None if they aren't for it
+fn listen_count(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> io::Result<Option<RawFd>> {
+    match (listen_pid, listen_fds) {
+        (Some(listen_pid), Some(count)) if listen_pid.parse() == Ok(pid) => match count.parse::<u16>() {
+            Ok(count) => Ok(Some(count.into())),
+            Err(_) => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid LISTEN_FDS '{count}'")))
+        },
+        _ => Ok(None)
+    }
+}
+
+
+// Take the listening sockets passed with LISTEN_FDS, if any
+pub fn listen_fds() -> io::Result<Vec<RawFd>> {
+    let listen_pid = env::var("LISTEN_PID").ok();
+    let listen_fds = env::var("LISTEN_FDS").ok();
+
+    let count = match listen_count(listen_pid.as_deref(), listen_fds.as_deref(), process::id())? {
+        Some(count) => count,
+        None => return Ok(vec![])
+    };
+
+    // Don't pass the sockets on to child processes
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let fds: Vec<_> = (LISTEN_FDS_START..LISTEN_FDS_START + count).collect();
+
+    for fd in &fds {
+        if unsafe { libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(fds)
+}
+
+
+// Send a state change like "READY=1" to the service manager, if there is one
+pub fn notify(state: &str) {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) if !path.is_empty() => path,
+        _ => return
+    };
+
+    if let Err(e) = send(&path, state) {
+        log::error!(Server, "Error Notifying Service Manager: {e}"; "socket" => path, "state" => state);
+    }
+}
+
+fn send(path: &str, state: &str) -> io::Result<usize> {
+    let socket = UnixDatagram::unbound()?;
+
+    match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+            socket.send_to_addr(state.as_bytes(), &SocketAddr::from_abstract_name(name)?)
+        },
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => Err(io::Error::new(io::ErrorKind::Unsupported, "Abstract sockets are only supported on Linux")),
+        None => socket.send_to(state.as_bytes(), path)
+    }
+}
+
+
+// How often to send "WATCHDOG=1", half the interval the service manager expects
+pub fn watchdog_interval() -> Option<Duration> {
+    if !for_this_process("WATCHDOG_PID") {
+        return None;
+    }
+
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+
+    match usec {
+        0 => None,
+        usec => Some(Duration::from_micros(usec / 2))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listen_counts() {
+        assert_eq!(listen_count(Some("42"), Some("2"), 42).unwrap(), Some(2));
+        assert_eq!(listen_count(Some("42"), Some("0"), 42).unwrap(), Some(0));
+
+        // Meant for another process, or not socket activated at all
+        assert_eq!(listen_count(Some("41"), Some("2"), 42).unwrap(), None);
+        assert_eq!(listen_count(Some("x"), Some("2"), 42).unwrap(), None);
+        assert_eq!(listen_count(None, Some("2"), 42).unwrap(), None);
+        assert_eq!(listen_count(Some("42"), None, 42).unwrap(), None);
+
+        assert!(listen_count(Some("42"), Some("two"), 42).is_err());
+        assert!(listen_count(Some("42"), Some("-1"), 42).is_err());
+    }
+
+    #[test]
+    fn notifications() {
+        let dir = env::temp_dir().join(format!("ws-notify-{}", process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("notify.sock");
+        let manager = UnixDatagram::bind(&path).unwrap();
+        manager.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let mut buf = [0; 64];
+        let mut receive = || {
+            let len = manager.recv(&mut buf).unwrap();
+            String::from_utf8_lossy(&buf[..len]).into_owned()
+        };
+
+        send(path.to_str().unwrap(), "READY=1").unwrap();
+        assert_eq!(receive(), "READY=1");
+
+        // Nothing else reads NOTIFY_SOCKET in tests
+        env::set_var("NOTIFY_SOCKET", &path);
+        notify("STOPPING=1");
+        env::remove_var("NOTIFY_SOCKET");
+        assert_eq!(receive(), "STOPPING=1");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn abstract_notifications() {
+        use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+        let name = format!("ws-notify-{}", process::id());
+        let manager = UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(&name).unwrap()).unwrap();
+        manager.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        send(&format!("@{name}"), "WATCHDOG=1").unwrap();
+
+        let mut buf = [0; 64];
+        let len = manager.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"WATCHDOG=1");
+    }
+}